@@ -0,0 +1,527 @@
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ethrex_blockchain::{
+    fork_choice::apply_fork_choice,
+    payload::{create_payload, BuildPayloadArgs},
+    Blockchain,
+};
+use ethrex_common::{
+    types::{Block, BlockHeader, ELASTICITY_MULTIPLIER},
+    Address, H256,
+};
+use ethrex_rlp::{decode::RLPDecode, encode::RLPEncode};
+use ethrex_storage::Store;
+use mojave_chain_utils::{Datadir, ProposalRecord};
+use tendermint_abci::{Application, ServerBuilder};
+use tendermint_proto::v0_38::abci::{
+    response_process_proposal::ProposalStatus, ExecTxResult, RequestCheckTx, RequestFinalizeBlock,
+    RequestInfo, RequestInitChain, RequestPrepareProposal, RequestProcessProposal, ResponseCheckTx,
+    ResponseCommit, ResponseFinalizeBlock, ResponseInfo, ResponseInitChain,
+    ResponsePrepareProposal, ResponseProcessProposal,
+};
+use tokio::{runtime::Handle, sync::mpsc};
+
+use crate::{
+    error::NodeError,
+    options::AbciOptions,
+    subsystem::{Subsystem, Subsystems},
+};
+
+/// ABCI code returned for transactions sent to CometBFT instead of the Mojave RPC.
+const CODE_TX_REJECTED: u32 = 1;
+
+/// ABCI++ application that lets CometBFT drive consensus over the Mojave chain.
+///
+/// Every CometBFT height carries exactly one Mojave block, RLP encoded as the only transaction
+/// of the proposal. Transactions are gathered by the Mojave mempool through the JSON-RPC, the
+/// CometBFT mempool is not used. The app hash is the state root of the last finalized block.
+///
+/// Proposals are recorded before they are returned to CometBFT, and a height that was already
+/// proposed, in a later round or before a restart, gets the same block again.
+///
+/// Proposed blocks are executed in ProcessProposal and stored off the canonical chain, so invalid
+/// blocks are rejected before they are voted on. FinalizeBlock then only makes them canonical.
+#[derive(Clone)]
+pub struct MojaveApp {
+    store: Store,
+    blockchain: Arc<Blockchain>,
+    coinbase_address: Address,
+    runtime: Handle,
+    proposal_record: Arc<Mutex<ProposalRecord>>,
+    /// Hash of the last block executed by ProcessProposal.
+    executed: Arc<Mutex<Option<H256>>>,
+    /// Errors after which this node can't follow consensus anymore and has to be stopped.
+    fatal_tx: mpsc::UnboundedSender<String>,
+}
+
+/// Starts the ABCI server CometBFT connects to.
+pub fn start_abci_server(
+    opts: &AbciOptions,
+    datadir: &Datadir,
+    store: Store,
+    blockchain: Arc<Blockchain>,
+    subsystems: &Subsystems,
+) -> Result<(), NodeError> {
+    let proposal_record_path = opts
+        .proposal_record
+        .clone()
+        .or_else(|| datadir.proposal_record())
+        .ok_or_else(|| {
+            NodeError::Config(
+                "An in-memory validator keeps no proposal record, set `--abci.proposal-record`"
+                    .to_owned(),
+            )
+        })?;
+    let proposal_record = ProposalRecord::load(&proposal_record_path).map_err(|e| {
+        NodeError::Consensus(format!(
+            "Failed to load proposal record {proposal_record_path:?}: {e}"
+        ))
+    })?;
+    let (last_proposed, _) = proposal_record.last_proposed();
+    tracing::info!("Last proposed at height {last_proposed}");
+
+    let (fatal_tx, mut fatal_rx) = mpsc::unbounded_channel();
+    let app = MojaveApp {
+        store,
+        blockchain,
+        coinbase_address: opts.coinbase_address,
+        runtime: Handle::current(),
+        proposal_record: Arc::new(Mutex::new(proposal_record)),
+        executed: Arc::default(),
+        fatal_tx,
+    };
+    let server = ServerBuilder::default()
+        .bind(opts.abci_addr, app)
+        .map_err(|e| {
+            NodeError::Consensus(format!(
+                "Failed to bind ABCI server to {}: {e}",
+                opts.abci_addr
+            ))
+        })?;
+    tracing::info!("Starting ABCI server at {}", opts.abci_addr);
+
+    // The server blocks on its connections, it gets a thread of its own.
+    subsystems.spawn(Subsystem::Consensus, |_| async move {
+        tokio::select! {
+            result = tokio::task::spawn_blocking(move || server.listen()) => match result {
+                Ok(result) => result.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            },
+            Some(e) = fatal_rx.recv() => Err(e),
+        }
+    });
+    Ok(())
+}
+
+impl MojaveApp {
+    fn fatal(&self, e: String) {
+        tracing::error!("{e}");
+        let _ = self.fatal_tx.send(e);
+    }
+
+    async fn head(&self) -> Result<BlockHeader, String> {
+        let number = self
+            .store
+            .get_latest_block_number()
+            .await
+            .map_err(|e| e.to_string())?;
+        self.store
+            .get_block_header(number)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Missing header of head block {number}"))
+    }
+
+    /// Builds the next block on top of the head with the mempool transactions.
+    async fn build_block(&self, timestamp: u64) -> Result<Block, String> {
+        let head = self.head().await?;
+        let args = BuildPayloadArgs {
+            parent: head.hash(),
+            // Block timestamps must increase even when CometBFT commits faster than a second.
+            timestamp: timestamp.max(head.timestamp + 1),
+            fee_recipient: self.coinbase_address,
+            random: H256::zero(),
+            withdrawals: Some(Vec::new()),
+            beacon_root: Some(H256::zero()),
+            version: 3,
+            elasticity_multiplier: ELASTICITY_MULTIPLIER,
+        };
+        let payload = create_payload(&args, &self.store).map_err(|e| e.to_string())?;
+        let result = self
+            .blockchain
+            .build_payload(payload)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(result.payload)
+    }
+
+    /// Decodes the block of a proposal and checks it extends the head at `height`.
+    async fn proposed_block(&self, txs: &[impl AsRef<[u8]>], height: i64) -> Result<Block, String> {
+        let [tx] = txs else {
+            return Err(format!(
+                "Proposal must carry exactly one block, got {} transactions",
+                txs.len()
+            ));
+        };
+        let block = Block::decode(tx.as_ref()).map_err(|e| format!("Invalid block: {e}"))?;
+        let head = self.head().await?;
+        if block.header.parent_hash != head.hash() || block.header.number as i64 != height {
+            return Err(format!(
+                "Block {} {:#x} doesn't extend head {} {:#x}",
+                block.header.number,
+                block.header.parent_hash,
+                head.number,
+                head.hash()
+            ));
+        }
+        Ok(block)
+    }
+
+    /// Executes `block` and stores it, with its state, off the canonical chain. Execution checks
+    /// the state root, receipts root and gas used of the header.
+    async fn execute(&self, block: &Block) -> Result<(), String> {
+        let block_hash = block.hash();
+        if self.last_executed() == Some(block_hash) {
+            return Ok(());
+        }
+        self.blockchain
+            .add_block(block)
+            .await
+            .map_err(|e| format!("Failed to execute block {block_hash:#x}: {e}"))?;
+        *self.executed.lock().unwrap_or_else(PoisonError::into_inner) = Some(block_hash);
+        Ok(())
+    }
+
+    fn last_executed(&self) -> Option<H256> {
+        *self.executed.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn finalize(&self, block: Block) -> Result<H256, String> {
+        let block_hash = block.hash();
+        let state_root = block.header.state_root;
+        // Already executed when it was processed, unless the node restarted in between.
+        self.execute(&block).await?;
+        apply_fork_choice(&self.store, block_hash, block_hash, block_hash)
+            .await
+            .map_err(|e| format!("Failed to apply fork choice to {block_hash:#x}: {e}"))?;
+        if let Err(e) = self.blockchain.remove_block_transactions_from_pool(&block) {
+            tracing::warn!("Failed to remove finalized transactions from the mempool: {e}");
+        }
+        tracing::info!("Finalized block {} {block_hash:#x}", block.header.number);
+        Ok(state_root)
+    }
+}
+
+impl Application for MojaveApp {
+    fn info(&self, _request: RequestInfo) -> ResponseInfo {
+        match self.runtime.block_on(self.head()) {
+            Ok(head) => ResponseInfo {
+                data: "mojave".to_owned(),
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                last_block_height: head.number as i64,
+                last_block_app_hash: head.state_root.as_bytes().to_vec().into(),
+                ..Default::default()
+            },
+            Err(e) => {
+                self.fatal(format!("Failed to read the head block for CometBFT: {e}"));
+                ResponseInfo::default()
+            }
+        }
+    }
+
+    fn init_chain(&self, request: RequestInitChain) -> ResponseInitChain {
+        let genesis = match self.runtime.block_on(self.head()) {
+            Ok(genesis) => genesis,
+            Err(e) => {
+                self.fatal(format!(
+                    "Failed to read the genesis block for CometBFT: {e}"
+                ));
+                return ResponseInitChain::default();
+            }
+        };
+        tracing::info!(
+            "CometBFT chain {} starts at height {}",
+            request.chain_id,
+            request.initial_height
+        );
+        ResponseInitChain {
+            app_hash: genesis.state_root.as_bytes().to_vec().into(),
+            ..Default::default()
+        }
+    }
+
+    fn check_tx(&self, _request: RequestCheckTx) -> ResponseCheckTx {
+        ResponseCheckTx {
+            code: CODE_TX_REJECTED,
+            log: "Send transactions to the Mojave JSON-RPC with eth_sendRawTransaction".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn prepare_proposal(&self, request: RequestPrepareProposal) -> ResponsePrepareProposal {
+        let timestamp = request
+            .time
+            .map(|time| time.seconds.max(0) as u64)
+            .unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default()
+            });
+
+        let height = request.height as u64;
+        let mut proposal_record = self
+            .proposal_record
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // This height was already proposed, maybe before a restart: the same block is proposed
+        // again rather than a different one.
+        if let (last, Some(proposed)) = proposal_record.last_proposed() {
+            if last == height {
+                return ResponsePrepareProposal {
+                    txs: vec![proposed.to_vec().into()],
+                };
+            }
+        }
+
+        let block = match self.runtime.block_on(self.build_block(timestamp)) {
+            Ok(block) => block,
+            Err(e) => {
+                tracing::error!("Failed to build block {}: {e}", request.height);
+                return ResponsePrepareProposal::default();
+            }
+        };
+        let encoded = block.encode_to_vec();
+        if encoded.len() as i64 > request.max_tx_bytes {
+            tracing::error!(
+                "Block {} is {} bytes, over the CometBFT limit of {}",
+                request.height,
+                encoded.len(),
+                request.max_tx_bytes
+            );
+            return ResponsePrepareProposal::default();
+        }
+        if let Err(e) = proposal_record.record(height, &encoded) {
+            tracing::error!("Refusing to propose block {}: {e}", request.height);
+            return ResponsePrepareProposal::default();
+        }
+        ResponsePrepareProposal {
+            txs: vec![encoded.into()],
+        }
+    }
+
+    fn process_proposal(&self, request: RequestProcessProposal) -> ResponseProcessProposal {
+        let result = self.runtime.block_on(async {
+            let block = self.proposed_block(&request.txs, request.height).await?;
+            self.execute(&block).await
+        });
+        let status = match result {
+            Ok(_) => ProposalStatus::Accept,
+            Err(e) => {
+                tracing::warn!("Rejected proposal at height {}: {e}", request.height);
+                ProposalStatus::Reject
+            }
+        };
+        ResponseProcessProposal {
+            status: status as i32,
+        }
+    }
+
+    fn finalize_block(&self, request: RequestFinalizeBlock) -> ResponseFinalizeBlock {
+        let result = self.runtime.block_on(async {
+            let block = self.proposed_block(&request.txs, request.height).await?;
+            self.finalize(block).await
+        });
+        // CometBFT only finalizes accepted proposals, failing here means this node diverged.
+        let state_root = match result {
+            Ok(state_root) => state_root,
+            Err(e) => {
+                self.fatal(format!("Failed to finalize height {}: {e}", request.height));
+                return ResponseFinalizeBlock::default();
+            }
+        };
+        ResponseFinalizeBlock {
+            tx_results: vec![ExecTxResult::default(); request.txs.len()],
+            app_hash: state_root.as_bytes().to_vec().into(),
+            ..Default::default()
+        }
+    }
+
+    fn commit(&self) -> ResponseCommit {
+        // Blocks are committed to the store in `finalize_block`.
+        ResponseCommit::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use ethrex::initializers::init_blockchain;
+    use ethrex_common::types::Genesis;
+    use ethrex_vm::EvmEngine;
+    use tendermint_abci::Application;
+
+    use super::*;
+    use crate::initializer::init_store;
+
+    const GENESIS: &str = include_str!("../../test_data/genesis.json");
+    const MAX_TX_BYTES: i64 = 1024 * 1024;
+
+    struct TestApp {
+        app: MojaveApp,
+        fatal_rx: mpsc::UnboundedReceiver<String>,
+        genesis: BlockHeader,
+        dir: PathBuf,
+    }
+
+    impl Drop for TestApp {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    async fn test_app(name: &str) -> TestApp {
+        let dir = std::env::temp_dir().join(format!("mojave-abci-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let genesis: Genesis = serde_json::from_str(GENESIS).unwrap();
+        let genesis_header = genesis.get_block().header;
+        let store = init_store(&Datadir::Memory, genesis).await.unwrap();
+        let blockchain = init_blockchain(EvmEngine::default(), store.clone());
+        let (fatal_tx, fatal_rx) = mpsc::unbounded_channel();
+        let app = MojaveApp {
+            store,
+            blockchain,
+            coinbase_address: Address::repeat_byte(0x42),
+            runtime: Handle::current(),
+            proposal_record: Arc::new(Mutex::new(
+                ProposalRecord::load(dir.join("mojave_proposal.json")).unwrap(),
+            )),
+            executed: Arc::default(),
+            fatal_tx,
+        };
+        TestApp {
+            app,
+            fatal_rx,
+            genesis: genesis_header,
+            dir,
+        }
+    }
+
+    /// Runs the ABCI calls of `script` on a blocking thread, as the ABCI server does.
+    async fn run<T: Send + 'static>(
+        app: &MojaveApp,
+        script: impl FnOnce(MojaveApp) -> T + Send + 'static,
+    ) -> T {
+        let app = app.clone();
+        tokio::task::spawn_blocking(move || script(app))
+            .await
+            .unwrap()
+    }
+
+    fn prepare(app: &MojaveApp, height: i64) -> Vec<u8> {
+        let response = app.prepare_proposal(RequestPrepareProposal {
+            height,
+            max_tx_bytes: MAX_TX_BYTES,
+            ..Default::default()
+        });
+        let [block] = response.txs.as_slice() else {
+            panic!("Expected one block in the proposal, got {:?}", response.txs);
+        };
+        block.to_vec()
+    }
+
+    fn process(app: &MojaveApp, height: i64, txs: Vec<Vec<u8>>) -> ProposalStatus {
+        let response = app.process_proposal(RequestProcessProposal {
+            height,
+            txs: txs.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        });
+        ProposalStatus::try_from(response.status).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drives_a_height_through_the_abci_calls() {
+        let mut test = test_app("height").await;
+        let genesis = test.genesis.clone();
+
+        let (info, init_chain, block, status, finalized, after) = run(&test.app, |app| {
+            let info = app.info(RequestInfo::default());
+            let init_chain = app.init_chain(RequestInitChain {
+                chain_id: "mojave-test".to_owned(),
+                initial_height: 1,
+                ..Default::default()
+            });
+            let block = prepare(&app, 1);
+            let status = process(&app, 1, vec![block.clone()]);
+            let finalized = app.finalize_block(RequestFinalizeBlock {
+                height: 1,
+                txs: vec![block.clone().into()],
+                ..Default::default()
+            });
+            app.commit();
+            let after = app.info(RequestInfo::default());
+            (info, init_chain, block, status, finalized, after)
+        })
+        .await;
+
+        assert_eq!(info.last_block_height, 0);
+        assert_eq!(
+            info.last_block_app_hash.as_ref(),
+            genesis.state_root.as_bytes()
+        );
+        assert_eq!(init_chain.app_hash.as_ref(), genesis.state_root.as_bytes());
+        assert_eq!(status, ProposalStatus::Accept);
+
+        let block = Block::decode(&block).unwrap();
+        assert_eq!(block.header.parent_hash, genesis.hash());
+        assert_eq!(
+            finalized.app_hash.as_ref(),
+            block.header.state_root.as_bytes()
+        );
+        assert_eq!(after.last_block_height, 1);
+        assert_eq!(after.last_block_app_hash, finalized.app_hash);
+        assert!(test.fatal_rx.try_recv().is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reproposes_the_block_of_a_proposed_height() {
+        let test = test_app("repropose").await;
+
+        let (first, second) = run(&test.app, |app| (prepare(&app, 1), prepare(&app, 1))).await;
+
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rejects_invalid_proposals() {
+        let mut test = test_app("reject").await;
+
+        let (statuses, head) = run(&test.app, |app| {
+            let block = prepare(&app, 1);
+            let mut bad_state = Block::decode(&block).unwrap();
+            bad_state.header.state_root = H256::repeat_byte(0xff);
+            let mut bad_parent = Block::decode(&block).unwrap();
+            bad_parent.header.parent_hash = H256::repeat_byte(0xff);
+
+            let statuses = [
+                process(&app, 1, vec![]),
+                process(&app, 1, vec![block.clone(), block.clone()]),
+                process(&app, 1, vec![vec![0xde, 0xad]]),
+                process(&app, 2, vec![block]),
+                process(&app, 1, vec![bad_parent.encode_to_vec()]),
+                process(&app, 1, vec![bad_state.encode_to_vec()]),
+            ];
+            let head = app.info(RequestInfo::default());
+            (statuses, head)
+        })
+        .await;
+
+        assert_eq!(statuses, [ProposalStatus::Reject; 6]);
+        assert_eq!(head.last_block_height, 0);
+        assert!(test.fatal_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,448 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use ethrex::initializers::init_blockchain;
+use ethrex_blockchain::fork_choice::apply_fork_choice;
+use ethrex_common::{types::Block, H256};
+use ethrex_rlp::{decode::RLPDecode, encode::RLPEncode};
+use ethrex_storage::Store;
+use ethrex_vm::EvmEngine;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use mojave_chain_utils::{Datadir, DatadirLock};
+
+use crate::{
+    db::migrate_datadir,
+    error::NodeError,
+    initializer::{init_store, lock_datadir},
+    networks::Network,
+};
+
+/// Interval between progress logs.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Imported blocks between fork choice updates. An interrupted import resumes after the last
+/// update, re-executing at most this many blocks.
+const FORK_CHOICE_INTERVAL: u64 = 256;
+
+/// Largest block accepted from a file, so a corrupt length can't exhaust the memory.
+const MAX_BLOCK_SIZE: usize = 256 * 1024 * 1024;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Locks and migrates the datadir of `network` and opens its store, the lock has to be held while it's used.
+async fn open_store(
+    datadir: &Datadir,
+    network: &Network,
+) -> Result<(Store, Option<DatadirLock>), NodeError> {
+    let lock = lock_datadir(datadir)?;
+    migrate_datadir(datadir)?;
+    let genesis = network.get_genesis()?;
+    Ok((init_store(datadir, genesis).await?, lock))
+}
+
+/// Writes the canonical blocks `from..=to` of the datadir to `path` as concatenated RLP blocks,
+/// gzip compressed when the file name ends with `.gz`.
+pub async fn export_blocks(
+    datadir: &Datadir,
+    network: &Network,
+    from: u64,
+    to: Option<u64>,
+    path: &Path,
+) -> Result<(), NodeError> {
+    if !datadir.exists() {
+        return Err(NodeError::Config(format!(
+            "Datadir {datadir} doesn't exist, there is nothing to export"
+        )));
+    }
+    let (store, _lock) = open_store(datadir, network).await?;
+    write_blocks_file(&store, from, to, path).await
+}
+
+/// [`export_blocks`] from an open store.
+async fn write_blocks_file(
+    store: &Store,
+    from: u64,
+    to: Option<u64>,
+    path: &Path,
+) -> Result<(), NodeError> {
+    let latest = store
+        .get_latest_block_number()
+        .await
+        .map_err(|e| NodeError::Storage(e.to_string()))?;
+    let to = to.unwrap_or(latest);
+    if from > to || to > latest {
+        return Err(NodeError::Config(format!(
+            "Can't export blocks {from} to {to}, the datadir has blocks 0 to {latest}"
+        )));
+    }
+
+    let write_error =
+        |e: io::Error| NodeError::Storage(format!("Failed to write blocks to {path:?}: {e}"));
+    let file = BufWriter::new(File::create(path).map_err(write_error)?);
+    if path.extension().is_some_and(|extension| extension == "gz") {
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        write_blocks(store, from, to, &mut encoder).await?;
+        encoder
+            .finish()
+            .and_then(|mut file| file.flush())
+            .map_err(write_error)?;
+    } else {
+        let mut file = file;
+        write_blocks(store, from, to, &mut file).await?;
+        file.flush().map_err(write_error)?;
+    }
+
+    tracing::info!("Exported blocks {from} to {to} to {path:?}");
+    Ok(())
+}
+
+async fn write_blocks(
+    store: &Store,
+    from: u64,
+    to: u64,
+    writer: &mut impl Write,
+) -> Result<(), NodeError> {
+    let mut progress = Progress::new("Exported");
+    for number in from..=to {
+        let block = store
+            .get_block_by_number(number)
+            .await
+            .map_err(|e| NodeError::Storage(e.to_string()))?
+            .ok_or_else(|| NodeError::Storage(format!("Canonical block {number} is missing")))?;
+        writer
+            .write_all(&block.encode_to_vec())
+            .map_err(|e| NodeError::Storage(format!("Failed to write block {number}: {e}")))?;
+        progress.tick(number);
+    }
+    Ok(())
+}
+
+/// Executes the blocks of an exported file on top of the datadir chain. Blocks the datadir
+/// already has are checked against it and skipped, so an interrupted import can be run again.
+pub async fn import_blocks(
+    datadir: &Datadir,
+    network: &Network,
+    path: &Path,
+) -> Result<(), NodeError> {
+    let (store, _lock) = open_store(datadir, network).await?;
+    read_blocks_file(&store, path).await
+}
+
+/// [`import_blocks`] into an open store.
+async fn read_blocks_file(store: &Store, path: &Path) -> Result<(), NodeError> {
+    let blockchain = init_blockchain(EvmEngine::LEVM, store.clone());
+
+    let read_error =
+        |e: io::Error| NodeError::Storage(format!("Failed to read blocks from {path:?}: {e}"));
+    let mut reader = BufReader::new(File::open(path).map_err(read_error)?);
+    let gzip = reader
+        .fill_buf()
+        .map_err(read_error)?
+        .starts_with(&GZIP_MAGIC);
+    let mut reader: Box<dyn Read> = if gzip {
+        Box::new(BufReader::new(GzDecoder::new(reader)))
+    } else {
+        Box::new(reader)
+    };
+
+    let latest = store
+        .get_latest_block_number()
+        .await
+        .map_err(|e| NodeError::Storage(e.to_string()))?;
+    if latest > 0 {
+        tracing::info!("Resuming import after block {latest}");
+    }
+
+    let mut progress = Progress::new("Imported");
+    let mut last_block = None;
+    while let Some(encoded) = read_rlp_item(&mut reader).map_err(read_error)? {
+        let block = Block::decode(&encoded)
+            .map_err(|e| NodeError::Storage(format!("Invalid block in {path:?}: {e}")))?;
+        let number = block.header.number;
+        let hash = block.hash();
+
+        if number <= latest {
+            let stored_hash = store
+                .get_block_header(number)
+                .map_err(|e| NodeError::Storage(e.to_string()))?
+                .map(|header| header.hash());
+            if stored_hash != Some(hash) {
+                return Err(NodeError::Config(format!(
+                    "Block {number} {hash:#x} of {path:?} isn't the one of the datadir chain"
+                )));
+            }
+            continue;
+        }
+
+        blockchain
+            .add_block(&block)
+            .await
+            .map_err(|e| NodeError::Storage(format!("Failed to import block {number}: {e}")))?;
+        if number % FORK_CHOICE_INTERVAL == 0 {
+            set_head(store, number, hash).await?;
+        }
+        last_block = Some((number, hash));
+        progress.tick(number);
+    }
+
+    match last_block {
+        Some((number, hash)) => {
+            set_head(store, number, hash).await?;
+            tracing::info!("Imported blocks up to {number} from {path:?}");
+        }
+        None => tracing::info!("The datadir already has every block of {path:?}"),
+    }
+    Ok(())
+}
+
+async fn set_head(store: &Store, number: u64, hash: H256) -> Result<(), NodeError> {
+    apply_fork_choice(store, hash, hash, hash)
+        .await
+        .map(|_| ())
+        .map_err(|e| NodeError::Storage(format!("Failed to set block {number} as head: {e}")))
+}
+
+/// Reads the next RLP list of `reader`, `None` at the end of the file.
+fn read_rlp_item(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut prefix = [0; 1];
+    match reader.read_exact(&mut prefix) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut item = prefix.to_vec();
+    let payload_len = match prefix[0] {
+        short @ 0xc0..=0xf7 => usize::from(short - 0xc0),
+        long @ 0xf8..=0xff => {
+            let mut len_bytes = vec![0; usize::from(long - 0xf7)];
+            reader.read_exact(&mut len_bytes)?;
+            item.extend_from_slice(&len_bytes);
+            len_bytes.iter().fold(0usize, |len, byte| {
+                len.saturating_mul(256).saturating_add(usize::from(*byte))
+            })
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected an RLP encoded block",
+            ))
+        }
+    };
+    if payload_len > MAX_BLOCK_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("block of {payload_len} bytes is over the limit of {MAX_BLOCK_SIZE}"),
+        ));
+    }
+
+    let start = item.len();
+    item.resize(start + payload_len, 0);
+    reader.read_exact(&mut item[start..])?;
+    Ok(Some(item))
+}
+
+/// Logs the block rate at most every `PROGRESS_INTERVAL`.
+struct Progress {
+    action: &'static str,
+    blocks: u64,
+    started: Instant,
+    last_log: Instant,
+}
+
+impl Progress {
+    fn new(action: &'static str) -> Self {
+        let now = Instant::now();
+        Self {
+            action,
+            blocks: 0,
+            started: now,
+            last_log: now,
+        }
+    }
+
+    fn tick(&mut self, number: u64) {
+        self.blocks += 1;
+        if self.last_log.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        self.last_log = Instant::now();
+        let rate = self.blocks as f64 / self.started.elapsed().as_secs_f64();
+        tracing::info!(
+            "{} {} blocks, at block {number} ({rate:.1} blocks/s)",
+            self.action,
+            self.blocks
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethrex_blockchain::payload::{create_payload, BuildPayloadArgs};
+    use ethrex_common::types::{Genesis, ELASTICITY_MULTIPLIER};
+    use mojave_chain_utils::TestDir;
+
+    use super::*;
+
+    const GENESIS: &str = include_str!("../../test_data/genesis.json");
+
+    fn read_items(bytes: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let mut reader = bytes;
+        let mut items = Vec::new();
+        while let Some(item) = read_rlp_item(&mut reader)? {
+            items.push(item);
+        }
+        Ok(items)
+    }
+
+    #[test]
+    fn reads_short_and_long_lists() {
+        let short = vec![0xc3, 0x01, 0x02, 0x03];
+        let mut long = vec![0xf8, 60];
+        long.extend([0x42; 60]);
+        let empty = vec![0xc0];
+
+        let file = [short.clone(), long.clone(), empty.clone()].concat();
+        assert_eq!(read_items(&file).unwrap(), [short, long, empty]);
+        assert!(read_items(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuses_truncated_items() {
+        for file in [&[0xc3, 0x01, 0x02][..], &[0xf8], &[0xf9, 0x01]] {
+            let error = read_items(file).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn refuses_oversized_length_prefixes() {
+        // A 4 GiB block and the largest length a prefix can hold, neither is allocated.
+        let four_gib = [0xfc, 0x01, 0x00, 0x00, 0x00, 0x00];
+        let largest = [0xff; 9];
+        for file in [&four_gib[..], &largest] {
+            let error = read_items(file).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn refuses_non_list_items() {
+        let error = read_items(&[0x80]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    async fn genesis_store() -> Store {
+        let genesis: Genesis = serde_json::from_str(GENESIS).unwrap();
+        init_store(&Datadir::Memory, genesis).await.unwrap()
+    }
+
+    /// Adds `count` empty blocks on top of the head of `store`, `interval` seconds apart.
+    async fn add_blocks(store: &Store, count: u64, interval: u64) {
+        let blockchain = init_blockchain(EvmEngine::LEVM, store.clone());
+        for _ in 0..count {
+            let number = store.get_latest_block_number().await.unwrap();
+            let head = store.get_block_header(number).unwrap().unwrap();
+            let args = BuildPayloadArgs {
+                parent: head.hash(),
+                timestamp: head.timestamp + interval,
+                fee_recipient: Default::default(),
+                random: H256::zero(),
+                withdrawals: Some(Vec::new()),
+                beacon_root: Some(H256::zero()),
+                version: 3,
+                elasticity_multiplier: ELASTICITY_MULTIPLIER,
+            };
+            let payload = create_payload(&args, store).unwrap();
+            let block = blockchain.build_payload(payload).await.unwrap().payload;
+            blockchain.add_block(&block).await.unwrap();
+            set_head(store, block.header.number, block.hash())
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn block_hashes(store: &Store) -> Vec<H256> {
+        let latest = store.get_latest_block_number().await.unwrap();
+        (0..=latest)
+            .map(|number| store.get_block_header(number).unwrap().unwrap().hash())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn export_import_round_trip() {
+        let dir = TestDir::new("block-file-round-trip");
+        let source = genesis_store().await;
+        add_blocks(&source, 3, 1).await;
+        let hashes = block_hashes(&source).await;
+
+        for name in ["blocks.rlp", "blocks.rlp.gz"] {
+            let path = dir.path().join(name);
+            write_blocks_file(&source, 0, None, &path).await.unwrap();
+            let gzip = std::fs::read(&path).unwrap().starts_with(&GZIP_MAGIC);
+            assert_eq!(gzip, name.ends_with(".gz"), "{name}");
+
+            let imported = genesis_store().await;
+            read_blocks_file(&imported, &path).await.unwrap();
+            assert_eq!(block_hashes(&imported).await, hashes, "{name}");
+
+            // Blocks the store already has are checked and skipped.
+            read_blocks_file(&imported, &path).await.unwrap();
+            assert_eq!(block_hashes(&imported).await, hashes, "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn import_resumes_after_a_partial_file() {
+        let dir = TestDir::new("block-file-resume");
+        let source = genesis_store().await;
+        add_blocks(&source, 3, 1).await;
+        let partial = dir.path().join("partial.rlp");
+        let full = dir.path().join("full.rlp");
+        write_blocks_file(&source, 0, Some(1), &partial)
+            .await
+            .unwrap();
+        write_blocks_file(&source, 0, None, &full).await.unwrap();
+
+        let imported = genesis_store().await;
+        read_blocks_file(&imported, &partial).await.unwrap();
+        assert_eq!(imported.get_latest_block_number().await.unwrap(), 1);
+        read_blocks_file(&imported, &full).await.unwrap();
+        assert_eq!(block_hashes(&imported).await, block_hashes(&source).await);
+    }
+
+    #[tokio::test]
+    async fn refuses_blocks_of_another_chain() {
+        let dir = TestDir::new("block-file-other-chain");
+        let source = genesis_store().await;
+        add_blocks(&source, 2, 1).await;
+        let path = dir.path().join("blocks.rlp");
+        write_blocks_file(&source, 0, None, &path).await.unwrap();
+
+        // Same genesis, but the blocks have other timestamps.
+        let other = genesis_store().await;
+        add_blocks(&other, 1, 5).await;
+
+        let error = read_blocks_file(&other, &path).await.unwrap_err();
+        assert!(matches!(error, NodeError::Config(_)), "{error}");
+    }
+
+    #[tokio::test]
+    async fn refuses_ranges_outside_the_chain() {
+        let dir = TestDir::new("block-file-range");
+        let store = genesis_store().await;
+        add_blocks(&store, 1, 1).await;
+        let path = dir.path().join("blocks.rlp");
+
+        for (from, to) in [(2, None), (0, Some(2)), (1, Some(0))] {
+            assert!(matches!(
+                write_blocks_file(&store, from, to, &path).await,
+                Err(NodeError::Config(_))
+            ));
+        }
+    }
+}
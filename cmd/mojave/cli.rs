@@ -1,20 +1,56 @@
-use clap::Parser;
-use tracing::Level;
+use std::{ffi::OsString, path::PathBuf};
 
-use crate::{command::Command, version::get_version};
+use clap::{CommandFactory, FromArgMatches, Parser};
+
+use crate::{
+    command::Command,
+    config::{find_config_path, ConfigFile},
+    error::NodeError,
+    keys::apply_keystores,
+    options::LogOptions,
+    version::get_version,
+};
 
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Parser)]
 #[command(name = "mojave", author = "1six Technologies", version=get_version(), about = "Mojave is a blockchain node implementation for the Mojave network")]
 pub struct CLI {
+    #[command(flatten)]
+    pub log_opts: LogOptions,
     #[arg(
-      long = "log.level",
-      default_value_t = Level::INFO,
-      value_name = "LOG_LEVEL",
-      help = "The verbosity level used for logs.",
-      long_help = "Possible values: info, debug, trace, warn, error",
-      help_heading = "Node options")]
-    pub log_level: Level,
+        long = "config",
+        global = true,
+        value_name = "CONFIG_FILE",
+        env = "MOJAVE_CONFIG",
+        help = "TOML file with values for any of the options, keyed by their long flag name.",
+        long_help = "YAML is not supported. Values given on the command line or through env vars take precedence over the file, which takes precedence over the defaults. Use `mojave config dump` to print the effective configuration.",
+        help_heading = "Node options"
+    )]
+    pub config: Option<PathBuf>,
     #[command(subcommand)]
     pub command: Command,
 }
+
+/// Builds the clap command with the values of the `--config` file and the keys of the
+/// `--*.keystore` files in `args` as defaults.
+pub fn command_with_config(args: &[OsString]) -> Result<clap::Command, NodeError> {
+    apply_keystores(command_with_config_file(args)?, args)
+}
+
+/// Builds the clap command with the values of the `--config` file in `args` as defaults.
+pub fn command_with_config_file(args: &[OsString]) -> Result<clap::Command, NodeError> {
+    let command = CLI::command();
+    match find_config_path(args) {
+        Some(path) => ConfigFile::load(&path)?.apply(command),
+        None => Ok(command),
+    }
+}
+
+impl CLI {
+    /// Parses the process args, exiting on invalid ones like `CLI::parse` does.
+    pub fn parse_with_config() -> Result<Self, NodeError> {
+        let args: Vec<OsString> = std::env::args_os().collect();
+        let matches = command_with_config(&args)?.get_matches_from(args);
+        Ok(CLI::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
+    }
+}
@@ -1,23 +1,39 @@
-use std::{future::IntoFuture, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    fs,
+    future::IntoFuture,
+    io::{self, Write},
+    path::PathBuf,
+    time::Duration,
+};
 
-use anyhow::Result;
-use clap::Subcommand;
+use clap::{ArgAction, Subcommand};
 use ethrex::{
-    initializers::{
-        get_local_node_record, get_signer, init_blockchain, init_rollup_store, init_store,
-    },
+    l2::SequencerOptions,
     utils::{store_node_config_file, NodeConfigFile},
 };
 use ethrex_l2::SequencerConfig;
-use ethrex_p2p::network::peer_table;
+use ethrex_p2p::{peer_handler::PeerHandler, sync::SyncMode, sync_manager::SyncManager};
 use ethrex_vm::EvmEngine;
-use mojave_chain_utils::resolve_datadir;
-use tokio::sync::Mutex;
-use tokio_util::task::TaskTracker;
+use mojave_chain_utils::Datadir;
 
 use crate::{
-    initializer::{get_local_p2p_node, init_metrics, init_network, init_rpc_api},
-    options::Options,
+    abci::start_abci_server,
+    block_file::{export_blocks, import_blocks},
+    config::ConfigCommand,
+    db::DbCommand,
+    error::NodeError,
+    follower::{follow_sequencer, SequencerClient},
+    genesis::GenesisCommand,
+    init::init_datadir,
+    initializer::{
+        get_sponsor_private_key, init_metrics, init_network, init_node, init_rpc_api, lock_datadir,
+        NodeHandles,
+    },
+    keys::{KeysCommand, SequencerKeystoreOptions},
+    options::{AbciOptions, DatadirOptions, FollowerOptions, Options},
+    shutdown::ShutdownSignals,
+    state_dump::{dump_state, BlockId},
+    subsystem::{Subsystem, Subsystems},
 };
 
 #[derive(Subcommand, Debug)]
@@ -26,113 +42,493 @@ pub enum Command {
     FullNode {
         #[command(flatten)]
         opts: Options,
+        #[command(flatten)]
+        follower_opts: FollowerOptions,
     },
     #[command(name = "sequencer", about = "Run a sequencer")]
     Sequencer {
         #[command(flatten)]
         opts: Options,
+        #[command(flatten)]
+        sequencer_opts: SequencerOptions,
+        // Only declares the keystore args: the committer and proof coordinator keys are
+        // required args of the ethrex sequencer options, so `keys::apply_keystores` decrypts
+        // them before parsing.
+        #[command(flatten)]
+        keystore_opts: SequencerKeystoreOptions,
+    },
+    #[command(
+        name = "validator",
+        about = "Run a validator driven by CometBFT over ABCI"
+    )]
+    Validator {
+        #[command(flatten)]
+        opts: Options,
+        #[command(flatten)]
+        abci_opts: AbciOptions,
+    },
+    #[command(
+        name = "init",
+        about = "Create a datadir with a genesis, JWT secret, node key and config file"
+    )]
+    Init {
+        #[command(flatten)]
+        datadir_opts: DatadirOptions,
+        #[arg(
+            long = "force",
+            help = "Overwrite an initialized datadir",
+            long_help = "Regenerate the genesis, JWT secret, node key and config file of an initialized datadir. The keystore and database are kept.",
+            action = ArgAction::SetTrue
+        )]
+        force: bool,
+    },
+    #[command(name = "genesis", about = "Build genesis files")]
+    Genesis {
+        #[command(subcommand)]
+        command: GenesisCommand,
+    },
+    #[command(
+        name = "removedb",
+        about = "Remove the database",
+        long_about = "Remove the databases of the datadir: the store, the rollup store and the chain record `chain.json`. The keystore, node key, genesis, JWT secret, config and every other file of the datadir are kept."
+    )]
+    RemoveDb {
+        #[command(flatten)]
+        datadir_opts: DatadirOptions,
+        #[arg(
+            long = "force",
+            help = "Force remove the database",
+            long_help = "Delete the database without confirmation.",
+            action = ArgAction::SetTrue
+        )]
+        force: bool,
+    },
+    #[command(name = "export", about = "Export canonical blocks to an RLP file")]
+    Export {
+        #[command(flatten)]
+        datadir_opts: DatadirOptions,
+        #[arg(
+            long = "from",
+            value_name = "BLOCK_NUMBER",
+            default_value_t = 0,
+            help = "First block to export."
+        )]
+        from: u64,
+        #[arg(
+            long = "to",
+            value_name = "BLOCK_NUMBER",
+            help = "Last block to export, the head of the chain by default."
+        )]
+        to: Option<u64>,
+        #[arg(
+            value_name = "FILE",
+            help = "File the blocks are written to, gzip compressed if it ends with `.gz`."
+        )]
+        file: PathBuf,
+    },
+    #[command(
+        name = "import",
+        about = "Import the blocks of an RLP file, re-executing them"
+    )]
+    Import {
+        #[command(flatten)]
+        datadir_opts: DatadirOptions,
+        #[arg(
+            value_name = "FILE",
+            help = "File written by `mojave export`, plain or gzip compressed.",
+            long_help = "Blocks the datadir already has are skipped, so an interrupted import resumes where it stopped."
+        )]
+        file: PathBuf,
+    },
+    #[command(
+        name = "dump-state",
+        about = "Dump every account of the state at a block as JSON lines"
+    )]
+    DumpState {
+        #[command(flatten)]
+        datadir_opts: DatadirOptions,
+        #[arg(
+            long = "block",
+            value_name = "BLOCK",
+            default_value = "latest",
+            help = "Number of the block whose state is dumped, or `latest`."
+        )]
+        block: BlockId,
+        #[arg(
+            long = "preimages",
+            value_name = "FILE",
+            help = "Addresses and storage slots to dump by their preimage, one hex value per line.",
+            long_help = "The state trie is keyed by hashes and ethrex doesn't record their preimages. Accounts and slots of the genesis alloc are known, list the others here, for example the addresses and slots an indexer saw. 20 byte values are addresses and 32 byte values storage slots."
+        )]
+        preimages: Option<PathBuf>,
+        #[arg(
+            long = "out",
+            value_name = "FILE",
+            default_value = "state.jsonl",
+            help = "File the accounts are written to, one JSON object per line.",
+            long_help = "Accounts have their `address` and storage slots their key when the preimage is known from the genesis or `--preimages`. The others are keyed by their hash only and can't be imported with `mojave genesis import-dump`."
+        )]
+        out: PathBuf,
+    },
+    #[command(
+        name = "keys",
+        about = "Manage the encrypted keys of the keystore",
+        long_about = "Manage the encrypted keys of the keystore, the `keystore` directory of the datadir."
+    )]
+    Keys {
+        #[command(flatten)]
+        datadir_opts: DatadirOptions,
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+    #[command(name = "db", about = "Maintain the datadir")]
+    Db {
+        #[command(flatten)]
+        datadir_opts: DatadirOptions,
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    #[command(name = "config", about = "Inspect the node configuration")]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
     },
 }
 
 impl Command {
-    pub async fn run(self) -> Result<()> {
+    pub async fn run(self) -> Result<(), NodeError> {
         match self {
-            Command::FullNode { opts } => {
-                if opts.evm == EvmEngine::REVM {
-                    panic!("Mojave doesn't support REVM, use LEVM instead.");
+            Command::FullNode {
+                mut opts,
+                follower_opts,
+            } => {
+                check_evm(&opts)?;
+                check_dev(&opts)?;
+                if opts.dev && follower_opts.sequencer_url.is_some() {
+                    return Err(NodeError::Config(
+                        "A dev node produces its own blocks, it can't follow a sequencer."
+                            .to_owned(),
+                    ));
                 }
 
-                let data_dir = resolve_datadir(&opts.datadir);
-                let rollup_store_dir = data_dir.clone() + "/rollup_store";
+                let signals = ShutdownSignals::new()?;
 
-                let genesis = opts.network.get_genesis()?;
-                let store = init_store(&data_dir, genesis).await;
-                let rollup_store = init_rollup_store(&rollup_store_dir).await;
+                let sequencer_client = follower_opts
+                    .sequencer_url
+                    .as_deref()
+                    .map(SequencerClient::new);
+                match &sequencer_client {
+                    Some(client) => match client.enode().await {
+                        Ok(enode) => opts.bootnodes.push(enode),
+                        Err(e) => tracing::warn!("Could not fetch the sequencer enode: {e}"),
+                    },
+                    None if opts.dev => {}
+                    None => tracing::warn!(
+                        "No sequencer URL specified. This node will not follow the sequencer head."
+                    ),
+                }
 
-                let blockchain = init_blockchain(opts.evm, store.clone());
+                let node = init_node(&opts).await?;
 
-                let signer = get_signer(&data_dir);
+                let subsystems = Subsystems::new();
 
-                let local_p2p_node = get_local_p2p_node(&opts, &signer);
+                let syncer = start_services(&opts, &node, &subsystems).await?;
 
-                let local_node_record = Arc::new(Mutex::new(get_local_node_record(
-                    &data_dir,
-                    &local_p2p_node,
-                    &signer,
-                )));
+                if let Some(client) = sequencer_client {
+                    // Not restarted: the follower only stops when the node diverged.
+                    let store = node.store.clone();
+                    subsystems.spawn(Subsystem::Follower, move |cancel_token| {
+                        follow_sequencer(follower_opts, client, store, syncer, cancel_token)
+                    });
+                }
 
-                let peer_table = peer_table(local_p2p_node.node_id());
+                wait_for_shutdown(&opts, node, subsystems, signals).await
+            }
+            Command::Sequencer {
+                mut opts,
+                sequencer_opts,
+                // Already decrypted into `sequencer_opts` by `keys::apply_keystores`.
+                keystore_opts: _,
+            } => {
+                check_evm(&opts)?;
+                if opts.dev {
+                    return Err(NodeError::Config(
+                        "`--dev` runs its own block producer, use it with `mojave full-node`."
+                            .to_owned(),
+                    ));
+                }
 
-                // TODO: Check every module starts properly.
-                let tracker = TaskTracker::new();
+                let signals = ShutdownSignals::new()?;
 
-                let cancel_token = tokio_util::sync::CancellationToken::new();
+                // The sequencer is the source of new blocks, it never snap syncs from peers.
+                if matches!(opts.syncmode, SyncMode::Snap) {
+                    tracing::warn!("Snap sync is not available for the sequencer, using full sync");
+                    opts.syncmode = SyncMode::Full;
+                }
 
-                init_rpc_api(
-                    &opts,
-                    peer_table.clone(),
-                    local_p2p_node.clone(),
-                    local_node_record.lock().await.clone(),
-                    store.clone(),
-                    blockchain.clone(),
-                    cancel_token.clone(),
-                    tracker.clone(),
-                    rollup_store.clone(),
-                )
-                .await;
+                let node = init_node(&opts).await?;
 
-                // Initialize metrics if enabled
-                if opts.metrics_enabled {
-                    init_metrics(&opts, tracker.clone());
-                }
+                let subsystems = Subsystems::new();
 
-                if opts.p2p_enabled {
-                    init_network(
-                        &opts,
-                        &opts.network,
-                        &data_dir,
-                        local_p2p_node,
-                        local_node_record.clone(),
-                        signer,
-                        peer_table.clone(),
-                        store.clone(),
-                        tracker.clone(),
-                        blockchain.clone(),
-                    )
-                    .await;
-                } else {
-                    tracing::info!("P2P is disabled");
-                }
+                start_services(&opts, &node, &subsystems).await?;
 
-                let l2_sequencer_cfg = SequencerConfig::from(opts.sequencer_opts);
+                let l2_sequencer_cfg = SequencerConfig::from(sequencer_opts);
 
                 let l2_sequencer = ethrex_l2::start_l2(
-                    store,
-                    rollup_store,
-                    blockchain,
+                    node.store.clone(),
+                    node.rollup_store.clone(),
+                    node.blockchain.clone(),
                     l2_sequencer_cfg,
                     #[cfg(feature = "metrics")]
                     format!("http://{}:{}", opts.http_addr, opts.http_port),
                 )
                 .into_future();
 
-                tracker.spawn(l2_sequencer);
-
-                tokio::select! {
-                    _ = tokio::signal::ctrl_c() => {
-                        tracing::info!("Server shut down started...");
-                        let node_config_path = PathBuf::from(data_dir + "/node_config.json");
-                        tracing::info!("Storing config at {:?}...", node_config_path);
-                        cancel_token.cancel();
-                        let node_config = NodeConfigFile::new(peer_table, local_node_record.lock().await.clone()).await;
-                        store_node_config_file(node_config, node_config_path).await;
-                        tokio::time::sleep(Duration::from_secs(1)).await;
-                        tracing::info!("Server shutting down!");
-                    }
+                subsystems.spawn(Subsystem::Sequencer, |_| l2_sequencer);
+
+                wait_for_shutdown(&opts, node, subsystems, signals).await
+            }
+            Command::Validator { opts, abci_opts } => {
+                check_evm(&opts)?;
+                if opts.dev {
+                    return Err(NodeError::Config(
+                        "`--dev` runs its own block producer, blocks of a validator come from CometBFT."
+                            .to_owned(),
+                    ));
                 }
+
+                let signals = ShutdownSignals::new()?;
+
+                let node = init_node(&opts).await?;
+
+                let subsystems = Subsystems::new();
+
+                start_services(&opts, &node, &subsystems).await?;
+
+                start_abci_server(
+                    &abci_opts,
+                    &node.datadir,
+                    node.store.clone(),
+                    node.blockchain.clone(),
+                    &subsystems,
+                )?;
+
+                wait_for_shutdown(&opts, node, subsystems, signals).await
+            }
+            Command::Init {
+                datadir_opts,
+                force,
+            } => init_datadir(&datadir_opts.datadir()?, datadir_opts.network()?, force),
+            Command::Genesis { command } => command.run(),
+            Command::RemoveDb {
+                datadir_opts,
+                force,
+            } => remove_db(&datadir_opts.datadir()?, force),
+            Command::Export {
+                datadir_opts,
+                from,
+                to,
+                file,
+            } => {
+                let datadir = datadir_opts.datadir()?;
+                export_blocks(&datadir, datadir_opts.network()?, from, to, &file).await
+            }
+            Command::Import { datadir_opts, file } => {
+                import_blocks(&datadir_opts.datadir()?, datadir_opts.network()?, &file).await
+            }
+            Command::DumpState {
+                datadir_opts,
+                block,
+                preimages,
+                out,
+            } => {
+                let datadir = datadir_opts.datadir()?;
+                dump_state(
+                    &datadir,
+                    datadir_opts.network()?,
+                    block,
+                    preimages.as_deref(),
+                    &out,
+                )
+                .await
             }
-            Command::Sequencer { .. } => todo!(),
+            Command::Keys {
+                datadir_opts,
+                command,
+            } => command.run(&datadir_opts.datadir()?),
+            Command::Db {
+                datadir_opts,
+                command,
+            } => command.run(&datadir_opts.datadir()?),
+            Command::Config { command } => command.run(),
+        }
+    }
+}
+
+fn remove_db(datadir: &Datadir, force: bool) -> Result<(), NodeError> {
+    let Some(data_dir) = datadir.path().filter(|path| path.exists()) else {
+        tracing::info!("Database at {datadir} doesn't exist, nothing to remove");
+        return Ok(());
+    };
+    let _lock = lock_datadir(datadir)?;
+
+    if !force {
+        print!(
+            "Are you sure you want to remove the database at {data_dir:?}? Keys and config files are kept. [y/N] "
+        );
+        io::stdout()
+            .flush()
+            .map_err(|e| NodeError::Storage(e.to_string()))?;
+        let mut answer = String::new();
+        io::stdin()
+            .read_line(&mut answer)
+            .map_err(|e| NodeError::Storage(e.to_string()))?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            tracing::info!("Database removal aborted");
+            return Ok(());
+        }
+    }
+
+    let remove_error = |e: io::Error| {
+        NodeError::Storage(format!("Failed to remove database at {data_dir:?}: {e}"))
+    };
+
+    for path in datadir.databases() {
+        if path.is_dir() {
+            fs::remove_dir_all(&path).map_err(remove_error)?;
+        } else if path.exists() {
+            fs::remove_file(&path).map_err(remove_error)?;
+        }
+    }
+
+    tracing::info!("Database at {:?} removed", data_dir);
+    Ok(())
+}
+
+fn check_evm(opts: &Options) -> Result<(), NodeError> {
+    if opts.evm == EvmEngine::REVM {
+        return Err(NodeError::Config(
+            "Mojave doesn't support REVM, use LEVM instead.".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+fn check_dev(opts: &Options) -> Result<(), NodeError> {
+    if opts.dev && !cfg!(feature = "dev") {
+        return Err(NodeError::Config(
+            "Build the binary with the `dev` feature in order to use the `--dev` cli's argument."
+                .to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Starts the RPC API, the metrics server and the P2P network shared by every node role.
+/// Returns the sync manager of the RPC so other tasks can sync without starting a second one.
+async fn start_services(
+    opts: &Options,
+    node: &NodeHandles,
+    subsystems: &Subsystems,
+) -> Result<SyncManager, NodeError> {
+    let sponsor_private_key = get_sponsor_private_key(opts, node.chain_id)?;
+    // Syncing from peers is stopped along with the P2P network
+    let syncer = SyncManager::new(
+        PeerHandler::new(node.peer_table.clone()),
+        opts.syncmode.clone(),
+        subsystems.cancel_token(Subsystem::P2P),
+        node.blockchain.clone(),
+        node.store.clone(),
+    )
+    .await;
+
+    init_rpc_api(
+        opts,
+        node.peer_table.clone(),
+        node.local_p2p_node.clone(),
+        node.local_node_record.lock().await.clone(),
+        node.store.clone(),
+        node.blockchain.clone(),
+        subsystems,
+        node.rollup_store.clone(),
+        sponsor_private_key,
+        syncer.clone(),
+    )?;
+
+    // Initialize metrics if enabled
+    if opts.metrics_enabled {
+        init_metrics(opts, subsystems);
+    }
+
+    if opts.dev {
+        // A dev chain has no peers, the node produces every block itself.
+        #[cfg(feature = "dev")]
+        crate::dev::init_block_producer(
+            opts,
+            node.store.clone(),
+            node.blockchain.clone(),
+            subsystems,
+        )?;
+        tracing::info!("P2P is disabled in dev mode");
+    } else if opts.p2p_enabled {
+        init_network(
+            opts,
+            &node.datadir,
+            node.local_p2p_node.clone(),
+            node.local_node_record.clone(),
+            node.signer.clone(),
+            node.peer_table.clone(),
+            node.store.clone(),
+            subsystems,
+            node.blockchain.clone(),
+        )
+        .await?;
+    } else {
+        tracing::info!("P2P is disabled");
+    }
+
+    Ok(syncer)
+}
+
+/// Waits for a shutdown signal or a crashed subsystem and stops the subsystems in order: RPC
+/// first so no new requests come in, then block production and P2P, and finally the store.
+async fn wait_for_shutdown(
+    opts: &Options,
+    node: NodeHandles,
+    subsystems: Subsystems,
+    mut signals: ShutdownSignals,
+) -> Result<(), NodeError> {
+    let result = tokio::select! {
+        signal = signals.recv() => {
+            tracing::info!("Received {signal}, server shut down started...");
+            Ok(())
         }
-        Ok(())
+        exit = subsystems.exited() => {
+            tracing::error!("{exit}, server shut down started...");
+            Err(NodeError::from(exit))
+        }
+    };
+
+    let timeout = Duration::from_secs(opts.shutdown_timeout);
+    for subsystem in Subsystem::ALL {
+        subsystems.stop(subsystem, timeout).await;
     }
+
+    if let Some(node_config_path) = node.datadir.node_config() {
+        tracing::info!("Storing config at {:?}...", node_config_path);
+        let node_config = NodeConfigFile::new(
+            node.peer_table.clone(),
+            node.local_node_record.lock().await.clone(),
+        )
+        .await;
+        store_node_config_file(node_config, node_config_path).await;
+    }
+
+    // Store writes are committed per transaction, dropping the last handles closes the databases.
+    tracing::info!("Closing store...");
+    drop(node);
+
+    tracing::info!("Server shutting down!");
+
+    result
 }
@@ -0,0 +1,54 @@
+use clap::Subcommand;
+use mojave_chain_utils::{run_migrations, Datadir, MigrationError, DATADIR_VERSION};
+
+use crate::{error::NodeError, initializer::lock_datadir, version::get_version};
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    #[command(
+        name = "migrate",
+        about = "Upgrade the datadir layout to the version of this build"
+    )]
+    Migrate,
+}
+
+impl DbCommand {
+    pub fn run(self, datadir: &Datadir) -> Result<(), NodeError> {
+        match self {
+            DbCommand::Migrate => {
+                if !datadir.exists() {
+                    return Err(NodeError::Config(format!(
+                        "Datadir {datadir} doesn't exist, there is nothing to migrate"
+                    )));
+                }
+                let _lock = lock_datadir(datadir)?;
+                let from = migrate_datadir(datadir)?;
+                if from == DATADIR_VERSION {
+                    println!("Datadir {datadir} is already at version {DATADIR_VERSION}");
+                } else {
+                    println!("Migrated datadir {datadir} from version {from} to {DATADIR_VERSION}");
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Runs the migrations `datadir` is missing and returns the version it had. The datadir has to be
+/// locked, and one written by a newer build is refused.
+pub fn migrate_datadir(datadir: &Datadir) -> Result<u32, NodeError> {
+    let written_by = format!("mojave/{}", get_version());
+    run_migrations(datadir, &written_by, |migration| {
+        tracing::info!(
+            "Migrating datadir {datadir} to version {}: {}",
+            migration.version,
+            migration.description
+        )
+    })
+    .map_err(|e| match e {
+        MigrationError::TooNew { .. } => {
+            NodeError::Config(format!("{e}. Upgrade Mojave to open datadir {datadir}."))
+        }
+        e => NodeError::Storage(format!("Failed to migrate datadir {datadir}: {e}")),
+    })
+}
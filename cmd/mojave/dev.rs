@@ -0,0 +1,254 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ethrex_blockchain::Blockchain;
+use ethrex_common::{
+    types::{blobs_bundle::kzg_commitment_to_versioned_hash, Genesis, GenesisAccount},
+    Address, H256, U256,
+};
+use ethrex_dev::utils::engine_client::EngineClient;
+use ethrex_rpc::types::{
+    fork_choice::{ForkChoiceState, PayloadAttributesV3},
+    payload::PayloadValidationStatus,
+};
+use ethrex_storage::Store;
+use keccak_hash::keccak;
+use secp256k1::SecretKey;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    error::NodeError,
+    initializer::{get_authrpc_socket_addr, read_jwt_secret},
+    keys::address_from_key,
+    options::Options,
+    subsystem::{Subsystem, Subsystems},
+};
+
+/// Balance of every prefunded dev account, in ether.
+const DEV_ACCOUNT_BALANCE: u64 = 10_000;
+/// How often the mempool is checked when blocks are produced on each transaction.
+const MEMPOOL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Consecutive block production failures before the producer gives up.
+const MAX_TRIES: u32 = 3;
+
+pub struct DevAccount {
+    pub address: Address,
+    pub private_key: SecretKey,
+}
+
+/// Deterministic test accounts, the same on every run so wallets and scripts can hardcode them.
+pub fn dev_accounts(count: usize) -> Vec<DevAccount> {
+    (0..count)
+        .map(|i| {
+            let seed = keccak(format!("mojave dev account {i}"));
+            let private_key = SecretKey::from_slice(seed.as_bytes())
+                .expect("keccak output is a valid secp256k1 private key");
+            DevAccount {
+                address: address_from_key(&private_key),
+                private_key,
+            }
+        })
+        .collect()
+}
+
+/// Adds the dev accounts to the genesis allocations and prints them.
+pub fn prefund_accounts(genesis: &mut Genesis, accounts: &[DevAccount]) {
+    let balance = U256::from(DEV_ACCOUNT_BALANCE) * U256::exp10(18);
+    for account in accounts {
+        genesis.alloc.insert(
+            account.address,
+            GenesisAccount {
+                code: Default::default(),
+                storage: Default::default(),
+                balance,
+                nonce: 0,
+            },
+        );
+    }
+
+    println!("\nAvailable Accounts\n==================\n");
+    for (i, account) in accounts.iter().enumerate() {
+        println!("({i}) {:#x} ({DEV_ACCOUNT_BALANCE} ETH)", account.address);
+    }
+    println!("\nPrivate Keys\n==================\n");
+    for (i, account) in accounts.iter().enumerate() {
+        println!("({i}) 0x{}", account.private_key.display_secret());
+    }
+    println!();
+}
+
+/// When the dev block producer builds a new block.
+#[derive(Debug, Clone, Copy)]
+pub enum BlockTrigger {
+    Interval(Duration),
+    OnTransaction,
+}
+
+impl BlockTrigger {
+    fn from_opts(opts: &Options) -> Self {
+        match opts.dev_block_time {
+            Some(block_time) => BlockTrigger::Interval(Duration::from_millis(block_time)),
+            None => BlockTrigger::OnTransaction,
+        }
+    }
+}
+
+/// Starts the dev block producer as the sequencer of the local chain.
+pub fn init_block_producer(
+    opts: &Options,
+    store: Store,
+    blockchain: Arc<Blockchain>,
+    subsystems: &Subsystems,
+) -> Result<(), NodeError> {
+    let authrpc_addr = get_authrpc_socket_addr(opts)?;
+    let jwt_secret = read_jwt_secret(&opts.authrpc_jwtsecret)?;
+    let engine_client = EngineClient::new(&format!("http://{authrpc_addr}"), jwt_secret.into());
+    let trigger = BlockTrigger::from_opts(opts);
+
+    match trigger {
+        BlockTrigger::Interval(interval) => {
+            tracing::info!("Producing dev blocks every {interval:?}")
+        }
+        BlockTrigger::OnTransaction => tracing::info!("Producing a dev block on each transaction"),
+    }
+    subsystems.spawn(Subsystem::Sequencer, move |cancel_token| {
+        start_block_producer(engine_client, store, blockchain, trigger, cancel_token)
+    });
+    Ok(())
+}
+
+/// Produces blocks through the engine API of this node, in place of a consensus client, until
+/// `cancel_token` is cancelled. A block being built when it is is finished first.
+async fn start_block_producer(
+    engine_client: EngineClient,
+    store: Store,
+    blockchain: Arc<Blockchain>,
+    trigger: BlockTrigger,
+    cancel_token: CancellationToken,
+) -> Result<(), NodeError> {
+    let latest_block_number = store
+        .get_latest_block_number()
+        .await
+        .map_err(|e| NodeError::Storage(e.to_string()))?;
+    let mut head_block_hash = store
+        .get_canonical_block_hash(latest_block_number)
+        .await
+        .map_err(|e| NodeError::Storage(e.to_string()))?
+        .ok_or_else(|| NodeError::Storage("Missing canonical head block".to_owned()))?;
+
+    let mut last_timestamp = 0;
+    let mut tries = 0;
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => return Ok(()),
+            _ = wait_for_trigger(trigger, &blockchain) => {}
+        }
+
+        // Block timestamps must increase even when several blocks are built within a second.
+        let timestamp = unix_timestamp().max(last_timestamp + 1);
+        match produce_block(&engine_client, head_block_hash, timestamp).await {
+            Ok(block_hash) => {
+                tracing::info!("Produced dev block {block_hash:#x}");
+                head_block_hash = block_hash;
+                last_timestamp = timestamp;
+                tries = 0;
+            }
+            Err(e) => {
+                tries += 1;
+                tracing::warn!("Failed to produce dev block ({tries}/{MAX_TRIES}): {e}");
+                if tries >= MAX_TRIES {
+                    return Err(NodeError::Rpc(format!(
+                        "Dev block producer gave up after {MAX_TRIES} tries: {e}"
+                    )));
+                }
+            }
+        }
+    }
+}
+
+async fn wait_for_trigger(trigger: BlockTrigger, blockchain: &Blockchain) {
+    match trigger {
+        BlockTrigger::Interval(interval) => tokio::time::sleep(interval).await,
+        BlockTrigger::OnTransaction => loop {
+            tokio::time::sleep(MEMPOOL_POLL_INTERVAL).await;
+            if blockchain
+                .mempool
+                .content()
+                .is_ok_and(|txs| !txs.is_empty())
+            {
+                break;
+            }
+        },
+    }
+}
+
+async fn produce_block(
+    engine_client: &EngineClient,
+    head_block_hash: H256,
+    timestamp: u64,
+) -> Result<H256, String> {
+    let parent_beacon_block_root = H256::zero();
+    let payload_attributes = PayloadAttributesV3 {
+        timestamp,
+        suggested_fee_recipient: Address::default(),
+        parent_beacon_block_root: Some(parent_beacon_block_root),
+        ..Default::default()
+    };
+    let fork_choice_response = engine_client
+        .engine_forkchoice_updated_v3(fork_choice_state(head_block_hash), Some(payload_attributes))
+        .await
+        .map_err(|e| format!("engine_forkchoiceUpdatedV3 failed: {e}"))?;
+    let payload_id = fork_choice_response
+        .payload_id
+        .ok_or("engine_forkchoiceUpdatedV3 returned no payload id")?;
+
+    let payload_response = engine_client
+        .engine_get_payload_v4(payload_id)
+        .await
+        .map_err(|e| format!("engine_getPayloadV4 failed: {e}"))?;
+    let block_hash = payload_response.execution_payload.block_hash;
+    let blob_versioned_hashes = payload_response
+        .blobs_bundle
+        .unwrap_or_default()
+        .commitments
+        .iter()
+        .map(kzg_commitment_to_versioned_hash)
+        .collect();
+
+    let payload_status = engine_client
+        .engine_new_payload_v4(
+            payload_response.execution_payload,
+            blob_versioned_hashes,
+            parent_beacon_block_root,
+        )
+        .await
+        .map_err(|e| format!("engine_newPayloadV4 failed: {e}"))?;
+    if !matches!(payload_status.status, PayloadValidationStatus::Valid) {
+        return Err(format!("Produced block is not valid: {payload_status:?}"));
+    }
+
+    // Make the block canonical right away, without waiting for the next one to be built.
+    engine_client
+        .engine_forkchoice_updated_v3(fork_choice_state(block_hash), None)
+        .await
+        .map_err(|e| format!("engine_forkchoiceUpdatedV3 failed: {e}"))?;
+
+    Ok(block_hash)
+}
+
+fn fork_choice_state(head_block_hash: H256) -> ForkChoiceState {
+    ForkChoiceState {
+        head_block_hash,
+        safe_block_hash: head_block_hash,
+        finalized_block_hash: head_block_hash,
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,57 @@
+use ethrex_common::types::GenesisError;
+
+use crate::subsystem::{Subsystem, SubsystemExit};
+
+/// Errors that stop the node. Every variant maps to its own process exit code so
+/// supervisors can tell configuration mistakes apart from runtime failures.
+#[derive(Debug, thiserror::Error)]
+pub enum NodeError {
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error("Invalid genesis: {0}")]
+    Genesis(#[from] GenesisError),
+    #[error("Storage error: {0}")]
+    Storage(String),
+    #[error("P2P network error: {0}")]
+    Network(String),
+    #[error("RPC error: {0}")]
+    Rpc(String),
+    #[error("L2 sequencer error: {0}")]
+    L2(String),
+    #[error("Consensus error: {0}")]
+    Consensus(String),
+    #[error("Failed to install signal handlers: {0}")]
+    Signal(#[from] std::io::Error),
+    #[error("Subsystem crashed: {0}")]
+    SubsystemExited(SubsystemExit),
+}
+
+impl NodeError {
+    /// Codes 1 and 2 are left to panics and clap usage errors.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            NodeError::Config(_) | NodeError::Genesis(_) => 10,
+            NodeError::Storage(_) => 3,
+            NodeError::Network(_) => 4,
+            NodeError::Rpc(_) => 5,
+            NodeError::L2(_) => 6,
+            NodeError::Signal(_) => 7,
+            NodeError::SubsystemExited(_) => 8,
+            NodeError::Consensus(_) => 9,
+        }
+    }
+}
+
+/// A subsystem that stopped for good fails the node with the error of its domain, so the exit
+/// code tells which part of the node failed.
+impl From<SubsystemExit> for NodeError {
+    fn from(exit: SubsystemExit) -> Self {
+        match exit.subsystem {
+            Subsystem::Rpc => NodeError::Rpc(exit.to_string()),
+            Subsystem::Sequencer => NodeError::L2(exit.to_string()),
+            Subsystem::Consensus | Subsystem::Follower => NodeError::Consensus(exit.to_string()),
+            Subsystem::P2P => NodeError::Network(exit.to_string()),
+            Subsystem::Metrics => NodeError::SubsystemExited(exit),
+        }
+    }
+}
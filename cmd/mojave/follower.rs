@@ -0,0 +1,181 @@
+use std::time::Duration;
+
+use ethrex_common::H256;
+use ethrex_p2p::{sync_manager::SyncManager, types::Node};
+use ethrex_storage::Store;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tokio_util::sync::CancellationToken;
+
+use crate::{error::NodeError, options::FollowerOptions};
+
+/// Minimal JSON-RPC client for the endpoints a follower needs from its sequencer.
+#[derive(Clone)]
+pub struct SequencerClient {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RemoteHeader {
+    number: String,
+    hash: H256,
+    state_root: H256,
+}
+
+#[derive(Deserialize)]
+struct RemoteNodeInfo {
+    enode: String,
+}
+
+impl SequencerClient {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.to_owned(),
+        }
+    }
+
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<T, NodeError> {
+        let request_error = |e: reqwest::Error| NodeError::Network(format!("{method} failed: {e}"));
+        let response: RpcResponse<T> = self
+            .client
+            .post(&self.url)
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": method,
+                "params": params,
+            }))
+            .send()
+            .await
+            .map_err(request_error)?
+            .json()
+            .await
+            .map_err(request_error)?;
+
+        if let Some(error) = response.error {
+            return Err(NodeError::Network(format!("{method} failed: {error}")));
+        }
+        response
+            .result
+            .ok_or_else(|| NodeError::Network(format!("{method} returned no result")))
+    }
+
+    /// Returns the number, hash and state root of the given block, or of the latest one.
+    async fn header(&self, number: Option<u64>) -> Result<(u64, H256, H256), NodeError> {
+        let tag = number.map_or_else(|| "latest".to_owned(), |n| format!("{n:#x}"));
+        let header: RemoteHeader = self
+            .request("eth_getBlockByNumber", json!([tag, false]))
+            .await?;
+        let number =
+            u64::from_str_radix(header.number.trim_start_matches("0x"), 16).map_err(|e| {
+                NodeError::Network(format!("Invalid block number {}: {e}", header.number))
+            })?;
+        Ok((number, header.hash, header.state_root))
+    }
+
+    pub async fn enode(&self) -> Result<Node, NodeError> {
+        let info: RemoteNodeInfo = self.request("admin_nodeInfo", json!([])).await?;
+        info.enode.parse().map_err(|e| {
+            NodeError::Network(format!("Invalid sequencer enode {}: {e:?}", info.enode))
+        })
+    }
+}
+
+/// Follows the sequencer head: triggers a sync from peers whenever the sequencer is ahead of the
+/// local chain and checks that the local state roots match the sequencer ones. `syncer` is shared
+/// with the RPC, so the follower and the engine API never run two sync cycles at once.
+///
+/// A block that differs from the sequencer one is an error: the node has diverged and must not
+/// keep serving its state.
+pub async fn follow_sequencer(
+    opts: FollowerOptions,
+    client: SequencerClient,
+    store: Store,
+    syncer: SyncManager,
+    cancel_token: CancellationToken,
+) -> Result<(), NodeError> {
+    let mut interval = tokio::time::interval(Duration::from_millis(opts.poll_interval_ms));
+    let mut last_checked = 0;
+
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            _ = interval.tick() => {}
+        }
+
+        let (remote_number, remote_hash, _) = match client.header(None).await {
+            Ok(head) => head,
+            Err(e) => {
+                tracing::warn!("Could not fetch the sequencer head: {e}");
+                continue;
+            }
+        };
+
+        let local_number = match store.get_latest_block_number().await {
+            Ok(number) => number,
+            Err(e) => {
+                tracing::error!("Could not read the latest local block: {e}");
+                continue;
+            }
+        };
+
+        if remote_number > local_number {
+            tracing::debug!(local_number, remote_number, "Syncing to sequencer head");
+            syncer.sync_to_head(remote_hash);
+        }
+
+        let check_number = local_number.min(remote_number);
+        if check_number > last_checked {
+            match check_state_root(&client, &store, check_number).await {
+                Ok(()) => last_checked = check_number,
+                Err(e @ NodeError::Consensus(_)) => return Err(e),
+                Err(e) => {
+                    tracing::warn!("Could not check state root of block {check_number}: {e}")
+                }
+            }
+        }
+    }
+
+    tracing::info!("Sequencer follower stopped");
+    Ok(())
+}
+
+/// Compares the local block `number` with the sequencer one, a mismatch is a `Consensus` error.
+async fn check_state_root(
+    client: &SequencerClient,
+    store: &Store,
+    number: u64,
+) -> Result<(), NodeError> {
+    let (_, remote_hash, remote_state_root) = client.header(Some(number)).await?;
+    let local_header = store
+        .get_block_header(number)
+        .map_err(|e| NodeError::Storage(e.to_string()))?
+        .ok_or_else(|| NodeError::Storage(format!("Block {number} is not stored locally")))?;
+
+    if local_header.state_root != remote_state_root {
+        return Err(NodeError::Consensus(format!(
+            "State root of block {number} is {:#x} locally but {remote_state_root:#x} on the sequencer",
+            local_header.state_root
+        )));
+    }
+    if local_header.hash() != remote_hash {
+        return Err(NodeError::Consensus(format!(
+            "Hash of block {number} is {:#x} locally but {remote_hash:#x} on the sequencer",
+            local_header.hash()
+        )));
+    }
+    Ok(())
+}
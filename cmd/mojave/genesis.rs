@@ -0,0 +1,615 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use clap::{ArgAction, Subcommand, ValueEnum};
+use ethrex_common::{types::Genesis, Address, H256, U256};
+use ethrex_storage::Store;
+use keccak_hash::keccak;
+use mojave_chain_utils::Datadir;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{error::NodeError, networks::Network, state_dump::alloc_from_dump};
+
+/// Gas limit of the genesis block of the Mojave networks.
+const DEFAULT_GAS_LIMIT: u64 = 150_000_000;
+
+/// Address of the beacon chain deposit contract, the genesis config needs it even without a
+/// beacon chain.
+const DEPOSIT_CONTRACT_ADDRESS: &str = "0x00000000219ab540356cbb839cbe05303d7705fa";
+
+/// Chain ids of the public Ethereum networks. A chain reusing one could replay their
+/// transactions.
+const ETHEREUM_CHAIN_IDS: [(u64, &str); 8] = [
+    (1, "mainnet"),
+    (3, "ropsten"),
+    (4, "rinkeby"),
+    (5, "goerli"),
+    (42, "kovan"),
+    (17000, "holesky"),
+    (11155111, "sepolia"),
+    (560048, "hoodi"),
+];
+
+/// A system contract deployed at genesis.
+///
+/// The bytecode is copied from the alloc of `test_data/genesis.json`, the ethrex L2 system
+/// contracts. Their sources and build live in ethrex, not in this repository, so a change to
+/// them has to be copied over from a genesis built by ethrex.
+pub struct Predeploy {
+    pub name: &'static str,
+    pub address: &'static str,
+    /// Hex encoded runtime bytecode.
+    pub code: &'static str,
+}
+
+pub const PREDEPLOYS: [Predeploy; 2] = [
+    Predeploy {
+        name: "L2ToL1Messenger",
+        address: "0x000000000000000000000000000000000000fffe",
+        code: include_str!("networks/predeploys/L2ToL1Messenger.bin"),
+    },
+    Predeploy {
+        name: "CommonBridgeL2",
+        address: "0x000000000000000000000000000000000000ffff",
+        code: include_str!("networks/predeploys/CommonBridgeL2.bin"),
+    },
+];
+
+impl Predeploy {
+    pub fn address(&self) -> Address {
+        Address::from_str(self.address).expect("Invalid predeploy address")
+    }
+
+    pub fn code(&self) -> Vec<u8> {
+        hex::decode(self.code.trim()).expect("Invalid predeploy bytecode")
+    }
+}
+
+/// Forks after the merge, in activation order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum GenesisFork {
+    Shanghai,
+    Cancun,
+    Prague,
+}
+
+impl GenesisFork {
+    /// Name of the fork in the `blobSchedule` of the genesis config.
+    fn name(&self) -> &'static str {
+        match self {
+            GenesisFork::Shanghai => "shanghai",
+            GenesisFork::Cancun => "cancun",
+            GenesisFork::Prague => "prague",
+        }
+    }
+
+    fn config_key(&self) -> &'static str {
+        match self {
+            GenesisFork::Shanghai => "shanghaiTime",
+            GenesisFork::Cancun => "cancunTime",
+            GenesisFork::Prague => "pragueTime",
+        }
+    }
+
+    /// Blob parameters of the fork as `(target, max, baseFeeUpdateFraction)`.
+    fn blob_schedule(&self) -> Option<(u64, u64, u64)> {
+        match self {
+            GenesisFork::Shanghai => None,
+            GenesisFork::Cancun => Some((3, 6, 3338477)),
+            GenesisFork::Prague => Some((6, 9, 5007716)),
+        }
+    }
+}
+
+fn parse_fork_time(s: &str) -> Result<(GenesisFork, u64), String> {
+    let (fork, time) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected FORK=TIMESTAMP, got `{s}`"))?;
+    let fork = <GenesisFork as ValueEnum>::from_str(fork.trim(), true)?;
+    let time = time
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid timestamp of {fork:?}: {e}"))?;
+    Ok((fork, time))
+}
+
+#[derive(Subcommand, Debug)]
+pub enum GenesisCommand {
+    #[command(
+        name = "new",
+        about = "Build a genesis file with the Mojave predeploys and print its hash"
+    )]
+    New {
+        #[arg(
+            long = "chain-id",
+            value_name = "CHAIN_ID",
+            help = "Chain id of the network."
+        )]
+        chain_id: u64,
+        #[arg(
+            long = "fork",
+            value_name = "FORK=TIMESTAMP",
+            value_parser = parse_fork_time,
+            value_delimiter = ',',
+            default_value = "shanghai=0,cancun=0,prague=0",
+            help = "Comma separated activation timestamps of the forks after the merge.",
+            long_help = "Forks that aren't listed are never activated. A fork needs the forks before it, activated at the same time or earlier."
+        )]
+        forks: Vec<(GenesisFork, u64)>,
+        #[arg(
+            long = "gas-limit",
+            value_name = "GAS_LIMIT",
+            default_value_t = DEFAULT_GAS_LIMIT,
+            help = "Gas limit of the genesis block."
+        )]
+        gas_limit: u64,
+        #[arg(
+            long = "timestamp",
+            value_name = "TIMESTAMP",
+            default_value_t = 0,
+            help = "Timestamp of the genesis block."
+        )]
+        timestamp: u64,
+        #[arg(
+            long = "alloc",
+            value_name = "ALLOC_FILE",
+            help = "CSV or JSON file with the accounts funded at genesis.",
+            long_help = "A `.csv` file has an `address,balance` line per account, with the balance in wei as a decimal or 0x prefixed hex number. A `.json` file maps addresses to accounts, like the `alloc` of a genesis file."
+        )]
+        alloc: Option<PathBuf>,
+        #[arg(
+            long = "out",
+            value_name = "GENESIS_FILE",
+            default_value = "genesis.json",
+            help = "File the genesis is written to."
+        )]
+        out: PathBuf,
+    },
+    #[command(
+        name = "import-dump",
+        about = "Turn a `mojave dump-state` file into a genesis alloc"
+    )]
+    ImportDump {
+        #[arg(
+            value_name = "DUMP_FILE",
+            help = "File written by `mojave dump-state`."
+        )]
+        dump: PathBuf,
+        #[arg(
+            long = "out",
+            value_name = "ALLOC_FILE",
+            default_value = "alloc.json",
+            help = "File the alloc is written to, usable as `mojave genesis new --alloc`."
+        )]
+        out: PathBuf,
+        #[arg(
+            long = "skip-unknown",
+            action = ArgAction::SetTrue,
+            help = "Leave out the accounts and storage slots only known by their hash.",
+            long_help = "Only accounts without code can be left out. A contract, or one of its storage slots, only known by its hash is still an error, the contract would run against a broken state."
+        )]
+        skip_unknown: bool,
+    },
+    #[command(
+        name = "check",
+        about = "Check a genesis file against the Mojave invariants"
+    )]
+    Check {
+        #[arg(
+            value_name = "GENESIS_FILE",
+            value_parser = clap::value_parser!(Network),
+            help = "Genesis file to check."
+        )]
+        network: Network,
+    },
+}
+
+impl GenesisCommand {
+    pub fn run(self) -> Result<(), NodeError> {
+        match self {
+            GenesisCommand::New {
+                chain_id,
+                forks,
+                gas_limit,
+                timestamp,
+                alloc,
+                out,
+            } => {
+                let mut accounts = match alloc {
+                    Some(path) => read_alloc(&path)?,
+                    None => BTreeMap::new(),
+                };
+                for predeploy in &PREDEPLOYS {
+                    let account = json!({
+                        "code": format!("0x{}", predeploy.code.trim()),
+                        "storage": {},
+                        "balance": "0x0",
+                        "nonce": "0x1",
+                    });
+                    if accounts.insert(predeploy.address(), account).is_some() {
+                        return Err(NodeError::Config(format!(
+                            "The alloc file funds {}, the address of the {} predeploy",
+                            predeploy.address, predeploy.name
+                        )));
+                    }
+                }
+
+                let genesis = json!({
+                    "config": chain_config(chain_id, forks)?,
+                    "nonce": "0x0",
+                    "timestamp": timestamp.to_string(),
+                    "extraData": "0x",
+                    "gasLimit": format!("{gas_limit:#x}"),
+                    "difficulty": "0x0",
+                    "mixHash": format!("{:#x}", H256::zero()),
+                    "coinbase": format!("{:#x}", Address::zero()),
+                    "alloc": accounts
+                        .into_iter()
+                        .map(|(address, account)| (format!("{address:#x}"), account))
+                        .collect::<serde_json::Map<_, _>>(),
+                });
+
+                let parsed: Genesis = serde_json::from_value(genesis.clone())
+                    .map_err(|e| NodeError::Config(format!("Built an invalid genesis: {e}")))?;
+                validate_genesis(&parsed)?;
+                let hash = parsed.get_block().hash();
+
+                let contents = serde_json::to_string_pretty(&genesis)
+                    .map_err(|e| NodeError::Config(format!("Failed to serialize genesis: {e}")))?;
+                fs::write(&out, contents + "\n").map_err(|e| {
+                    NodeError::Storage(format!("Failed to write genesis to {out:?}: {e}"))
+                })?;
+
+                println!("Genesis written to {}", out.display());
+                println!("Genesis hash: {hash:#x}");
+                Ok(())
+            }
+            GenesisCommand::ImportDump {
+                dump,
+                out,
+                skip_unknown,
+            } => alloc_from_dump(&dump, &out, skip_unknown),
+            GenesisCommand::Check { network } => {
+                let genesis = network.get_genesis()?;
+                validate_genesis(&genesis)?;
+                println!("Genesis {network} is valid");
+                println!("Genesis hash: {:#x}", genesis.get_block().hash());
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Checks the invariants of a Mojave chain that parsing the genesis doesn't, reporting every
+/// violation at once.
+pub fn validate_genesis(genesis: &Genesis) -> Result<(), NodeError> {
+    let config = &genesis.config;
+    let mut problems = Vec::new();
+
+    if let Some((_, name)) = ETHEREUM_CHAIN_IDS
+        .iter()
+        .find(|(chain_id, _)| *chain_id == config.chain_id)
+    {
+        problems.push(format!(
+            "chain id {} is the one of Ethereum {name}, pick an unused one",
+            config.chain_id
+        ));
+    }
+
+    let forks = [
+        (GenesisFork::Shanghai, config.shanghai_time),
+        (GenesisFork::Cancun, config.cancun_time),
+        (GenesisFork::Prague, config.prague_time),
+    ];
+    for pair in forks.windows(2) {
+        let [(fork, time), (next, next_time)] = pair else {
+            continue;
+        };
+        match (time, next_time) {
+            (None, Some(next_time)) => problems.push(format!(
+                "{next:?} is scheduled at {next_time} but {fork:?} never activates, schedule {fork:?} at {next_time} or earlier"
+            )),
+            (Some(time), Some(next_time)) if next_time < time => problems.push(format!(
+                "{next:?} at {next_time} activates before {fork:?} at {time}, forks must activate in order"
+            )),
+            _ => {}
+        }
+    }
+
+    let blob_schedules = [
+        (
+            GenesisFork::Cancun,
+            config.cancun_time,
+            &config.blob_schedule.cancun,
+        ),
+        (
+            GenesisFork::Prague,
+            config.prague_time,
+            &config.blob_schedule.prague,
+        ),
+    ];
+    for (fork, time, schedule) in blob_schedules {
+        if time.is_none() {
+            continue;
+        }
+        let key = format!("blobSchedule.{}", fork.name());
+        if schedule.target == 0 || schedule.target > schedule.max {
+            problems.push(format!(
+                "{key} target is {} with a max of {}, the target must be between 1 and the max",
+                schedule.target, schedule.max
+            ));
+        }
+        if schedule.base_fee_update_fraction == 0 {
+            problems.push(format!("{key} baseFeeUpdateFraction must not be 0"));
+        }
+    }
+
+    for predeploy in &PREDEPLOYS {
+        let expected_hash = keccak(predeploy.code());
+        match genesis.alloc.get(&predeploy.address()) {
+            None => problems.push(format!(
+                "the {} predeploy is missing at {}, build the genesis with `mojave genesis new`",
+                predeploy.name, predeploy.address
+            )),
+            Some(account) if keccak(&account.code) != expected_hash => problems.push(format!(
+                "the code hash of the {} predeploy at {} is {:#x} instead of {expected_hash:#x}, rebuild the genesis with `mojave genesis new`",
+                predeploy.name,
+                predeploy.address,
+                keccak(&account.code)
+            )),
+            Some(_) => {}
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(NodeError::Config(format!(
+        "Invalid Mojave genesis:\n  - {}",
+        problems.join("\n  - ")
+    )))
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct ChainRecord {
+    chain_id: u64,
+    genesis_hash: H256,
+}
+
+/// Checks that `genesis` is the one of the chain stored in `datadir`, recording it on the first
+/// start. A datadir without a record but with a database, from before chain records, is checked
+/// against the genesis block of `store`. A mismatch is an error: the database can only be used
+/// with its own genesis, so starting another chain needs a `removedb` first. In-memory datadirs
+/// start empty and are not checked.
+pub fn check_datadir_genesis(
+    datadir: &Datadir,
+    store: &Store,
+    genesis: &Genesis,
+) -> Result<(), NodeError> {
+    let Some(path) = datadir.chain_record() else {
+        return Ok(());
+    };
+    let record_error = |e: String| NodeError::Storage(format!("Chain record {path:?}: {e}"));
+
+    let record = ChainRecord {
+        chain_id: genesis.config.chain_id,
+        genesis_hash: genesis.get_block().hash(),
+    };
+    let mismatch = match fs::read_to_string(&path) {
+        Ok(contents) => {
+            let recorded: ChainRecord =
+                serde_json::from_str(&contents).map_err(|e| record_error(e.to_string()))?;
+            if recorded == record {
+                return Ok(());
+            }
+            Some(format!(
+                "Datadir {datadir} holds chain {} with genesis {:#x}, but `--network` resolves to chain {} with genesis {:#x}",
+                recorded.chain_id, recorded.genesis_hash, record.chain_id, record.genesis_hash
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let stored = store.get_block_header(0).map_err(|e| {
+                NodeError::Storage(format!(
+                    "Failed to read the genesis block of {datadir}: {e}"
+                ))
+            })?;
+            match stored.map(|header| header.hash()) {
+                Some(stored_hash) if stored_hash != record.genesis_hash => Some(format!(
+                    "Datadir {datadir} holds a chain with genesis {stored_hash:#x}, but `--network` resolves to genesis {:#x}",
+                    record.genesis_hash
+                )),
+                _ => None,
+            }
+        }
+        Err(e) => return Err(record_error(e.to_string())),
+    };
+    if let Some(mismatch) = mismatch {
+        return Err(NodeError::Config(format!(
+            "{mismatch}. Use the network of the datadir, another `--datadir`, or remove its database with `mojave removedb --datadir {datadir}` to start the new chain."
+        )));
+    }
+
+    let contents =
+        serde_json::to_string_pretty(&record).map_err(|e| record_error(e.to_string()))?;
+    path.parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, contents))
+        .map_err(|e| record_error(e.to_string()))?;
+    tracing::info!(
+        "Recorded chain {} with genesis {:#x} in {path:?}",
+        record.chain_id,
+        record.genesis_hash
+    );
+    Ok(())
+}
+
+fn chain_config(chain_id: u64, forks: Vec<(GenesisFork, u64)>) -> Result<Value, NodeError> {
+    let mut schedule = BTreeMap::new();
+    for (fork, time) in forks {
+        if schedule.insert(fork, time).is_some() {
+            return Err(NodeError::Config(format!(
+                "Fork {fork:?} is scheduled more than once"
+            )));
+        }
+    }
+
+    let mut config = json!({
+        "chainId": chain_id,
+        "homesteadBlock": 0,
+        "daoForkSupport": false,
+        "eip150Block": 0,
+        "eip155Block": 0,
+        "eip158Block": 0,
+        "byzantiumBlock": 0,
+        "constantinopleBlock": 0,
+        "petersburgBlock": 0,
+        "istanbulBlock": 0,
+        "berlinBlock": 0,
+        "londonBlock": 0,
+        "mergeNetsplitBlock": 0,
+        "terminalTotalDifficulty": 0,
+        "terminalTotalDifficultyPassed": true,
+        "depositContractAddress": DEPOSIT_CONTRACT_ADDRESS,
+    });
+    let mut blob_schedule = serde_json::Map::new();
+    let mut previous: Option<(GenesisFork, u64)> = None;
+    for fork in GenesisFork::value_variants() {
+        let Some(&time) = schedule.get(fork) else {
+            if let Some(later) = schedule.keys().find(|later| *later > fork) {
+                return Err(NodeError::Config(format!(
+                    "Fork {later:?} is scheduled without {fork:?}"
+                )));
+            }
+            continue;
+        };
+        if let Some((previous_fork, previous_time)) = previous.filter(|(_, t)| *t > time) {
+            return Err(NodeError::Config(format!(
+                "Fork {fork:?} at {time} activates before {previous_fork:?} at {previous_time}"
+            )));
+        }
+        previous = Some((*fork, time));
+
+        config[fork.config_key()] = time.into();
+        if let Some((target, max, base_fee_update_fraction)) = fork.blob_schedule() {
+            blob_schedule.insert(
+                fork.name().to_owned(),
+                json!({
+                    "target": target,
+                    "max": max,
+                    "baseFeeUpdateFraction": base_fee_update_fraction,
+                }),
+            );
+        }
+    }
+    if !blob_schedule.is_empty() {
+        config["blobSchedule"] = blob_schedule.into();
+    }
+    Ok(config)
+}
+
+/// Reads the accounts of a CSV or JSON alloc file.
+fn read_alloc(path: &Path) -> Result<BTreeMap<Address, Value>, NodeError> {
+    let alloc_error = |e: String| NodeError::Config(format!("Invalid alloc file {path:?}: {e}"));
+
+    let contents = fs::read_to_string(path)
+        .map_err(|e| NodeError::Config(format!("Failed to read alloc file {path:?}: {e}")))?;
+    let entries: Vec<(String, Value)> =
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("csv") => read_csv_alloc(&contents).map_err(alloc_error)?,
+            Some("json") => serde_json::from_str::<serde_json::Map<String, Value>>(&contents)
+                .map_err(|e| alloc_error(e.to_string()))?
+                .into_iter()
+                .collect(),
+            _ => return Err(alloc_error("expected a .csv or .json file".to_owned())),
+        };
+
+    let mut accounts = BTreeMap::new();
+    for (address, account) in entries {
+        let parsed = Address::from_str(address.trim())
+            .map_err(|e| alloc_error(format!("invalid address {address}: {e}")))?;
+        if !account.is_object() {
+            return Err(alloc_error(format!("account {address} isn't an object")));
+        }
+        if accounts.insert(parsed, account).is_some() {
+            return Err(alloc_error(format!("account {address} is listed twice")));
+        }
+    }
+    Ok(accounts)
+}
+
+/// Parses `address,balance` lines, skipping blank lines, `#` comments and an `address` header.
+fn read_csv_alloc(contents: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut entries = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line_error = |e: &str| format!("line {}: {e}", index + 1);
+        let (address, balance) = line
+            .split_once(',')
+            .ok_or_else(|| line_error("expected `address,balance`"))?;
+        let (address, balance) = (address.trim(), balance.trim());
+        if index == 0 && address.eq_ignore_ascii_case("address") {
+            continue;
+        }
+        let balance = match balance.strip_prefix("0x") {
+            Some(hex) => U256::from_str_radix(hex, 16).map_err(|e| e.to_string()),
+            None => U256::from_dec_str(balance).map_err(|e| e.to_string()),
+        }
+        .map_err(|e| line_error(&format!("invalid balance {balance}: {e}")))?;
+        entries.push((
+            address.to_owned(),
+            json!({ "balance": format!("{balance:#x}") }),
+        ));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn predeploys_match_the_test_genesis() {
+        let genesis: Genesis =
+            serde_json::from_str(include_str!("../../test_data/genesis.json")).unwrap();
+
+        for predeploy in &PREDEPLOYS {
+            let account = genesis
+                .alloc
+                .get(&predeploy.address())
+                .unwrap_or_else(|| panic!("{} is missing", predeploy.name));
+            assert_eq!(account.code, predeploy.code(), "{}", predeploy.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn datadir_refuses_another_genesis() {
+        let dir = mojave_chain_utils::TestDir::new("chain-record");
+        let datadir = Datadir::Disk(dir.path().to_owned());
+        let genesis: Genesis =
+            serde_json::from_str(include_str!("../../test_data/genesis.json")).unwrap();
+        let store = Store::new("memory", ethrex_storage::EngineType::InMemory).unwrap();
+
+        // The first start records the chain, later starts with the same genesis pass.
+        check_datadir_genesis(&datadir, &store, &genesis).unwrap();
+        assert!(datadir.chain_record().unwrap().exists());
+        check_datadir_genesis(&datadir, &store, &genesis).unwrap();
+
+        let mut other = genesis.clone();
+        other.config.chain_id += 1;
+        let error = check_datadir_genesis(&datadir, &store, &other).unwrap_err();
+        assert!(matches!(error, NodeError::Config(_)));
+        assert!(error.to_string().contains("mojave removedb"), "{error}");
+
+        // The record is kept, the datadir still refuses the other genesis.
+        assert!(check_datadir_genesis(&datadir, &store, &other).is_err());
+        check_datadir_genesis(&datadir, &store, &genesis).unwrap();
+    }
+}
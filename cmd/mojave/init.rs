@@ -0,0 +1,122 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use mojave_chain_utils::Datadir;
+use secp256k1::SecretKey;
+
+use crate::{
+    db::migrate_datadir, error::NodeError, genesis::validate_genesis, initializer::lock_datadir,
+    networks::Network,
+};
+
+/// Creates the datadir of a node of `network` with its genesis, JWT secret, P2P node key and a
+/// config file pointing the node at them. The keystore, if any, is left untouched.
+pub fn init_datadir(datadir: &Datadir, network: &Network, force: bool) -> Result<(), NodeError> {
+    let (Some(genesis_path), Some(jwt_secret_path), Some(node_key_path), Some(config_path)) = (
+        datadir.genesis(),
+        datadir.jwt_secret(),
+        datadir.node_key(),
+        datadir.config(),
+    ) else {
+        return Err(NodeError::Config(
+            "An in-memory datadir can't be initialized, set `--datadir` to a directory".to_owned(),
+        ));
+    };
+
+    // Any of the files written by `mojave init` marks the datadir as initialized.
+    let initialized: Vec<String> = [
+        &genesis_path,
+        &jwt_secret_path,
+        &node_key_path,
+        &config_path,
+    ]
+    .into_iter()
+    .filter(|path| path.exists())
+    .map(|path| path.display().to_string())
+    .collect();
+    if !initialized.is_empty() && !force {
+        return Err(NodeError::Config(format!(
+            "Datadir {datadir} is already initialized ({} exist), use `--force` to overwrite it",
+            initialized.join(", ")
+        )));
+    }
+
+    // The genesis is parsed first so an invalid one doesn't leave a half initialized datadir.
+    let path = network.get_genesis_path();
+    validate_genesis(&network.get_genesis()?)?;
+    let genesis = fs::read_to_string(path)
+        .map_err(|e| NodeError::Config(format!("Failed to read genesis file {path:?}: {e}")))?;
+
+    // Locking creates the datadir, which then gets the manifest of this version.
+    let _lock = lock_datadir(datadir)?;
+    migrate_datadir(datadir)?;
+
+    let init_error =
+        |path: &Path, e: io::Error| NodeError::Storage(format!("Failed to write {path:?}: {e}"));
+
+    write_file(&genesis_path, genesis.as_bytes(), false)
+        .map_err(|e| init_error(&genesis_path, e))?;
+
+    let jwt_secret: [u8; 32] = rand::random();
+    write_file(&jwt_secret_path, hex::encode(jwt_secret).as_bytes(), true)
+        .map_err(|e| init_error(&jwt_secret_path, e))?;
+
+    // ethrex reads the node key as the raw secret bytes.
+    let node_key = SecretKey::new(&mut rand::thread_rng());
+    write_file(&node_key_path, &node_key.secret_bytes(), true)
+        .map_err(|e| init_error(&node_key_path, e))?;
+
+    let config = starter_config(
+        &datadir.to_string(),
+        &genesis_path.to_string_lossy(),
+        &jwt_secret_path.to_string_lossy(),
+    );
+    write_file(&config_path, config.as_bytes(), false).map_err(|e| init_error(&config_path, e))?;
+
+    tracing::info!("Initialized datadir {datadir} for {network}");
+    tracing::info!("Start the node with `mojave --config {config_path:?} full-node`");
+    Ok(())
+}
+
+fn starter_config(datadir: &str, network: &str, jwt_secret: &str) -> String {
+    let quote = |s: &str| toml::Value::String(s.to_owned()).to_string();
+    format!(
+        r#"# Written by `mojave init`. Keys are the long flag names of the node options, see
+# `mojave full-node --help`. Values given on the command line or through env vars take
+# precedence over this file.
+
+datadir = {datadir}
+network = {network}
+
+[authrpc]
+jwtsecret = {jwt_secret}
+
+# [http]
+# addr = "localhost"
+# port = "8545"
+
+# [p2p]
+# port = "30303"
+"#,
+        datadir = quote(datadir),
+        network = quote(network),
+        jwt_secret = quote(jwt_secret),
+    )
+}
+
+/// Writes `contents` to `path`, readable only by the owner when it's a secret.
+pub(crate) fn write_file(path: &Path, contents: &[u8], secret: bool) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if secret {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = secret;
+    options.open(path)?.write_all(contents)
+}
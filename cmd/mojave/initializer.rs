@@ -1,46 +1,198 @@
 use std::{
     fs, io,
     net::{Ipv4Addr, SocketAddr, ToSocketAddrs},
-    path::PathBuf,
+    path::Path,
     sync::Arc,
 };
 
-use anyhow::Result;
-use ethrex::utils::{get_client_version, read_jwtsecret_file, read_node_config_file};
+use ethrex::{
+    initializers::{get_local_node_record, init_blockchain, init_rollup_store},
+    utils::{get_client_version, read_node_config_file},
+};
 use ethrex_blockchain::Blockchain;
-use ethrex_common::Address;
+use ethrex_common::{types::Genesis, Address};
 use ethrex_p2p::{
     kademlia::KademliaTable,
-    network::{public_key_from_signing_key, P2PContext},
+    network::{peer_table, public_key_from_signing_key, P2PContext},
     peer_handler::PeerHandler,
     sync_manager::SyncManager,
     types::{Node, NodeRecord},
 };
-use ethrex_storage::Store;
+use ethrex_rpc::{GasTipEstimator, NodeData, RpcApiContext};
+use ethrex_storage::{EngineType, Store};
 use ethrex_storage_rollup::StoreRollup;
 use k256::ecdsa::SigningKey;
 use local_ip_address::local_ip;
+use mojave_chain_utils::{Datadir, DatadirLock, DatadirLockError};
+use secp256k1::SecretKey;
 use tokio::sync::Mutex;
-use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tokio_util::task::TaskTracker;
 
 use crate::{
-    networks::{self, Network},
+    db::migrate_datadir,
+    error::NodeError,
+    genesis::{check_datadir_genesis, validate_genesis},
+    init::write_file,
     options::Options,
+    subsystem::{Subsystem, Subsystems},
+    ws,
 };
 
-pub fn get_bootnodes(opts: &Options, network: &Network, data_dir: &str) -> Vec<Node> {
-    let mut bootnodes: Vec<Node> = opts.bootnodes.clone();
+/// Storage, execution and P2P identity handles shared by every node role.
+pub struct NodeHandles {
+    pub datadir: Datadir,
+    pub store: Store,
+    pub rollup_store: StoreRollup,
+    pub blockchain: Arc<Blockchain>,
+    /// Chain id of the genesis the node runs.
+    pub chain_id: u64,
+    pub signer: SigningKey,
+    pub local_p2p_node: Node,
+    pub local_node_record: Arc<Mutex<NodeRecord>>,
+    pub peer_table: Arc<Mutex<KademliaTable>>,
+    /// Declared last so it's released after the stores are closed.
+    pub lock: Option<DatadirLock>,
+}
+
+pub async fn init_node(opts: &Options) -> Result<NodeHandles, NodeError> {
+    // Dev chains are thrown away on exit, both stores live in memory.
+    let datadir = if opts.dev {
+        Datadir::Memory
+    } else {
+        opts.datadir_opts.datadir()?
+    };
+    let lock = lock_datadir(&datadir)?;
+    migrate_datadir(&datadir)?;
+
+    #[allow(unused_mut)]
+    let mut genesis = opts.datadir_opts.network()?.get_genesis()?;
+    validate_genesis(&genesis)?;
+    let chain_id = genesis.config.chain_id;
+    #[cfg(feature = "dev")]
+    if opts.dev {
+        crate::dev::prefund_accounts(&mut genesis, &crate::dev::dev_accounts(opts.dev_accounts));
+    }
+    let store = init_store(&datadir, genesis).await?;
+    let rollup_store = init_rollup_store(&datadir.rollup_store().to_string()).await;
+
+    let blockchain = init_blockchain(opts.evm, store.clone());
+
+    let signer = load_node_key(&datadir)?;
+
+    let local_p2p_node = get_local_p2p_node(opts, &signer)?;
 
-    match network {
-        Network::Mainnet => {
-            tracing::info!("Adding mainnet preset bootnodes");
-            bootnodes.extend(networks::MAINNET_BOOTNODES.clone());
+    let local_node_record = Arc::new(Mutex::new(get_local_node_record(
+        &datadir.to_string(),
+        &local_p2p_node,
+        &signer,
+    )));
+
+    let peer_table = peer_table(local_p2p_node.node_id());
+
+    Ok(NodeHandles {
+        datadir,
+        store,
+        rollup_store,
+        blockchain,
+        chain_id,
+        signer,
+        local_p2p_node,
+        local_node_record,
+        peer_table,
+        lock,
+    })
+}
+
+/// Opens the store of `datadir`, checks it holds the chain of `genesis` and adds `genesis` to it
+/// if it's empty.
+pub async fn init_store(datadir: &Datadir, genesis: Genesis) -> Result<Store, NodeError> {
+    let engine_type = match datadir {
+        Datadir::Disk(_) => EngineType::Libmdbx,
+        Datadir::Memory => EngineType::InMemory,
+    };
+    let store = Store::new(&datadir.to_string(), engine_type)
+        .map_err(|e| NodeError::Storage(format!("Failed to open the store in {datadir}: {e}")))?;
+    check_datadir_genesis(datadir, &store, &genesis)?;
+    store
+        .add_initial_state(genesis)
+        .await
+        .map_err(|e| NodeError::Storage(format!("Failed to store the genesis state: {e}")))?;
+    Ok(store)
+}
+
+/// Reads the P2P node key of `datadir`, generating it on the first start. An in-memory node gets
+/// a new P2P identity on every start.
+pub fn load_node_key(datadir: &Datadir) -> Result<SigningKey, NodeError> {
+    let Some(path) = datadir.node_key() else {
+        return Ok(SigningKey::random(&mut rand::rngs::OsRng));
+    };
+    if !path.exists() {
+        let key = SigningKey::random(&mut rand::rngs::OsRng);
+        write_file(&path, &key.to_bytes(), true)
+            .map_err(|e| NodeError::Storage(format!("Failed to write node key {path:?}: {e}")))?;
+        return Ok(key);
+    }
+    let bytes = fs::read(&path)
+        .map_err(|e| NodeError::Storage(format!("Failed to read node key {path:?}: {e}")))?;
+    SigningKey::from_slice(&bytes)
+        .map_err(|e| NodeError::Config(format!("Invalid node key {path:?}: {e}")))
+}
+
+/// Reads the hex encoded JWT secret of the engine API, generating it if `path` doesn't exist.
+pub fn read_jwt_secret(path: &str) -> Result<Vec<u8>, NodeError> {
+    match fs::read_to_string(path) {
+        Ok(contents) => {
+            let contents = contents.trim();
+            hex::decode(contents.strip_prefix("0x").unwrap_or(contents))
+                .map_err(|e| NodeError::Config(format!("Invalid JWT secret {path:?}: {e}")))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let secret: [u8; 32] = rand::random();
+            write_file(Path::new(path), hex::encode(secret).as_bytes(), true).map_err(|e| {
+                NodeError::Storage(format!("Failed to write JWT secret {path:?}: {e}"))
+            })?;
+            Ok(secret.to_vec())
         }
-        Network::Testnet => {
-            tracing::info!("Adding testnet preset bootnodes");
-            bootnodes.extend(networks::TESTNET_BOOTNODES.clone());
+        Err(e) => Err(NodeError::Storage(format!(
+            "Failed to read JWT secret {path:?}: {e}"
+        ))),
+    }
+}
+
+/// Locks `datadir` for this process so no other node or command opens its databases. In-memory
+/// datadirs have nothing to lock.
+pub fn lock_datadir(datadir: &Datadir) -> Result<Option<DatadirLock>, NodeError> {
+    let Some(path) = datadir.lock_file() else {
+        return Ok(None);
+    };
+    let lock = DatadirLock::acquire(path).map_err(|e| match e {
+        DatadirLockError::Held { .. } => NodeError::Config(format!(
+            "Datadir {datadir} is in use: {e}. Stop the other node or use another `--datadir`."
+        )),
+        DatadirLockError::Io(_) => {
+            NodeError::Storage(format!("Failed to lock datadir {datadir}: {e}"))
         }
-        _ => {}
+    })?;
+    if let Some(owner) = lock.stale_owner() {
+        tracing::warn!(
+            "Took over the lock of {owner} on datadir {datadir}, it didn't exit cleanly"
+        );
+    }
+    Ok(Some(lock))
+}
+
+pub fn get_bootnodes(opts: &Options, datadir: &Datadir) -> Result<Vec<Node>, NodeError> {
+    let mut bootnodes: Vec<Node> = opts.bootnodes.clone();
+
+    if let Some(path) = &opts.bootnodes_file {
+        tracing::info!("Adding bootnodes from file {:?}", path);
+        let contents = fs::read_to_string(path).map_err(|e| {
+            NodeError::Config(format!("Failed to read bootnodes file {path:?}: {e}"))
+        })?;
+        let file_bootnodes: Vec<Node> = serde_json::from_str(&contents).map_err(|e| {
+            NodeError::Config(format!("Failed to parse bootnodes file {path:?}: {e}"))
+        })?;
+        bootnodes.extend(file_bootnodes);
     }
 
     if bootnodes.is_empty() {
@@ -49,44 +201,40 @@ pub fn get_bootnodes(opts: &Options, network: &Network, data_dir: &str) -> Vec<N
         );
     }
 
-    let config_file = PathBuf::from(data_dir.to_owned() + "/node_config.json");
-
-    tracing::info!("Reading known peers from config file {:?}", config_file);
+    if let Some(config_file) = datadir.node_config() {
+        tracing::info!("Reading known peers from config file {:?}", config_file);
 
-    match read_node_config_file(config_file) {
-        Ok(ref mut config) => bootnodes.append(&mut config.known_peers),
-        Err(e) => tracing::error!("Could not read from peers file: {e}"),
-    };
+        match read_node_config_file(config_file) {
+            Ok(ref mut config) => bootnodes.append(&mut config.known_peers),
+            Err(e) => tracing::error!("Could not read from peers file: {e}"),
+        };
+    }
 
-    bootnodes
+    Ok(bootnodes)
 }
 
 #[allow(clippy::too_many_arguments)]
 pub async fn init_network(
     opts: &Options,
-    network: &Network,
-    data_dir: &str,
+    datadir: &Datadir,
     local_p2p_node: Node,
     local_node_record: Arc<Mutex<NodeRecord>>,
     signer: SigningKey,
     peer_table: Arc<Mutex<KademliaTable>>,
     store: Store,
-    tracker: TaskTracker,
+    subsystems: &Subsystems,
     blockchain: Arc<Blockchain>,
-) {
-    if opts.dev {
-        tracing::error!("Binary wasn't built with The feature flag `dev` enabled.");
-        panic!(
-            "Build the binary with the `dev` feature in order to use the `--dev` cli's argument."
-        );
-    }
-
-    let bootnodes = get_bootnodes(opts, network, data_dir);
+) -> Result<(), NodeError> {
+    let bootnodes = get_bootnodes(opts, datadir)?;
 
+    // ethrex spawns the discovery, RLPx listener and peer tasks on this tracker without handing
+    // back their join handles. Discovery and the listener live as long as the node, so the
+    // network is down once the tracker is empty.
+    let network_tasks = TaskTracker::new();
     let context = P2PContext::new(
         local_p2p_node,
         local_node_record,
-        tracker.clone(),
+        network_tasks.clone(),
         signer,
         peer_table.clone(),
         store,
@@ -94,26 +242,43 @@ pub async fn init_network(
         get_client_version(),
     );
 
-    context.set_fork_id().await.expect("Set fork id");
+    context
+        .set_fork_id()
+        .await
+        .map_err(|e| NodeError::Network(format!("Failed to set fork id: {e}")))?;
 
     ethrex_p2p::start_network(context, bootnodes)
         .await
-        .expect("Network starts");
+        .map_err(|e| NodeError::Network(format!("Failed to start P2P network: {e}")))?;
+
+    network_tasks.close();
+    subsystems.spawn(Subsystem::P2P, |_| async move {
+        network_tasks.wait().await;
+        Err::<(), _>(NodeError::Network(
+            "Discovery and the RLPx listener stopped".to_owned(),
+        ))
+    });
+    subsystems.spawn(Subsystem::P2P, |_| {
+        ethrex_p2p::periodically_show_peer_stats(peer_table)
+    });
 
-    tracker.spawn(ethrex_p2p::periodically_show_peer_stats(peer_table.clone()));
+    Ok(())
 }
 
-pub fn init_metrics(opts: &Options, tracker: TaskTracker) {
+pub fn init_metrics(opts: &Options, subsystems: &Subsystems) {
     tracing::info!(
         "Starting metrics server on {}:{}",
         opts.metrics_addr,
         opts.metrics_port
     );
-    let metrics_api = ethrex_metrics::api::start_prometheus_metrics_api(
-        opts.metrics_addr.clone(),
-        opts.metrics_port.clone(),
-    );
-    tracker.spawn(metrics_api);
+    let metrics_addr = opts.metrics_addr.clone();
+    let metrics_port = opts.metrics_port.clone();
+    subsystems.spawn_with_restart(Subsystem::Metrics, move |_| {
+        ethrex_metrics::api::start_prometheus_metrics_api(
+            metrics_addr.clone(),
+            metrics_port.clone(),
+        )
+    });
 }
 
 pub fn parse_socket_addr(addr: &str, port: &str) -> io::Result<SocketAddr> {
@@ -127,16 +292,22 @@ pub fn parse_socket_addr(addr: &str, port: &str) -> io::Result<SocketAddr> {
         ))
 }
 
-pub fn get_local_p2p_node(opts: &Options, signer: &SigningKey) -> Node {
-    let udp_socket_addr = parse_socket_addr(&opts.discovery_addr, &opts.discovery_port)
-        .expect("Failed to parse discovery address and port");
-    let tcp_socket_addr =
-        parse_socket_addr(&opts.p2p_addr, &opts.p2p_port).expect("Failed to parse addr and port");
+fn invalid_socket_addr(name: &str, addr: &str, port: &str, e: io::Error) -> NodeError {
+    NodeError::Config(format!("Invalid {name} address {addr}:{port}: {e}"))
+}
+
+pub fn get_local_p2p_node(opts: &Options, signer: &SigningKey) -> Result<Node, NodeError> {
+    let udp_socket_addr =
+        parse_socket_addr(&opts.discovery_addr, &opts.discovery_port).map_err(|e| {
+            invalid_socket_addr("discovery", &opts.discovery_addr, &opts.discovery_port, e)
+        })?;
+    let tcp_socket_addr = parse_socket_addr(&opts.p2p_addr, &opts.p2p_port)
+        .map_err(|e| invalid_socket_addr("p2p", &opts.p2p_addr, &opts.p2p_port, e))?;
 
     // TODO: If hhtp.addr is 0.0.0.0 we get the local ip as the one of the node, otherwise we use the provided one.
     // This is fine for now, but we might need to support more options in the future.
     let p2p_node_ip = if udp_socket_addr.ip() == Ipv4Addr::new(0, 0, 0, 0) {
-        local_ip().expect("Failed to get local ip")
+        local_ip().map_err(|e| NodeError::Network(format!("Failed to get local ip: {e}")))?
     } else {
         udp_socket_addr.ip()
     };
@@ -155,26 +326,67 @@ pub fn get_local_p2p_node(opts: &Options, signer: &SigningKey) -> Node {
     let enode = node.enode_url();
     tracing::info!("Node: {enode}");
 
-    node
+    Ok(node)
 }
 
-pub fn get_authrpc_socket_addr(opts: &Options) -> SocketAddr {
+pub fn get_authrpc_socket_addr(opts: &Options) -> Result<SocketAddr, NodeError> {
     parse_socket_addr(&opts.authrpc_addr, &opts.authrpc_port)
-        .expect("Failed to parse authrpc address and port")
+        .map_err(|e| invalid_socket_addr("authrpc", &opts.authrpc_addr, &opts.authrpc_port, e))
 }
 
-pub fn get_http_socket_addr(opts: &Options) -> SocketAddr {
+pub fn get_http_socket_addr(opts: &Options) -> Result<SocketAddr, NodeError> {
     parse_socket_addr(&opts.http_addr, &opts.http_port)
-        .expect("Failed to parse http address and port")
+        .map_err(|e| invalid_socket_addr("http", &opts.http_addr, &opts.http_port, e))
+}
+
+pub fn get_ws_socket_addr(opts: &Options) -> Result<SocketAddr, NodeError> {
+    let ws_port = opts.ws_port.to_string();
+    parse_socket_addr(&opts.ws_host, &ws_port)
+        .map_err(|e| invalid_socket_addr("ws", &opts.ws_host, &ws_port, e))
 }
 
-pub fn get_valid_delegation_addresses(opts: &Options) -> Vec<Address> {
+/// Sponsor key that used to be the default, it's public so anyone can spend from it.
+const PUBLIC_SPONSOR_PRIVATE_KEY: &str =
+    "ffd790338a2798b648806fc8635ac7bf14af15425fed0c8f25bcc5febaa9b192";
+
+/// Chain ids of the development genesis files, the only chains besides `--dev` where the public
+/// sponsor key is accepted.
+const DEV_CHAIN_IDS: [u64; 1] = [1729];
+
+/// The sponsor key is handed to ethrex, which signs sponsored transactions in process, so it
+/// can't be held by a remote signer.
+pub fn get_sponsor_private_key(
+    opts: &Options,
+    chain_id: u64,
+) -> Result<Option<SecretKey>, NodeError> {
+    let Some(sponsor_private_key) = opts.sponsor_private_key else {
+        tracing::info!("No sponsor key configured, sponsored transactions are disabled");
+        return Ok(None);
+    };
+    if sponsor_private_key.display_secret().to_string() == PUBLIC_SPONSOR_PRIVATE_KEY {
+        if !opts.dev && !DEV_CHAIN_IDS.contains(&chain_id) {
+            return Err(NodeError::Config(format!(
+                "The sponsor key is the public development key, refusing to use it on chain {chain_id}"
+            )));
+        }
+        tracing::warn!(
+            "!!! The sponsor key is the public development key, anyone can spend its funds. Never use it outside of local testing !!!"
+        );
+    }
+    Ok(Some(sponsor_private_key))
+}
+
+pub fn get_valid_delegation_addresses(opts: &Options) -> Result<Vec<Address>, NodeError> {
     let Some(ref path) = opts.sponsorable_addresses_file_path else {
         tracing::warn!("No valid addresses provided, ethrex_SendTransaction will always fail");
-        return Vec::new();
+        return Ok(Vec::new());
     };
     let addresses: Vec<Address> = fs::read_to_string(path)
-        .unwrap_or_else(|_| panic!("Failed to load file {path}"))
+        .map_err(|e| {
+            NodeError::Config(format!(
+                "Failed to load sponsorable addresses file {path}: {e}"
+            ))
+        })?
         .lines()
         .filter(|line| !line.trim().is_empty())
         .map(|line| line.to_string().parse::<Address>())
@@ -183,48 +395,122 @@ pub fn get_valid_delegation_addresses(opts: &Options) -> Vec<Address> {
     if addresses.is_empty() {
         tracing::warn!("No valid addresses provided, ethrex_SendTransaction will always fail");
     }
-    addresses
+    Ok(addresses)
 }
 
 #[allow(clippy::too_many_arguments)]
-pub async fn init_rpc_api(
+pub fn init_rpc_api(
     opts: &Options,
     peer_table: Arc<Mutex<KademliaTable>>,
     local_p2p_node: Node,
     local_node_record: NodeRecord,
     store: Store,
     blockchain: Arc<Blockchain>,
-    cancel_token: CancellationToken,
-    tracker: TaskTracker,
+    subsystems: &Subsystems,
     rollup_store: StoreRollup,
-) {
+    sponsor_private_key: Option<SecretKey>,
+    syncer: SyncManager,
+) -> Result<(), NodeError> {
+    let http_addr = get_http_socket_addr(opts)?;
+    let authrpc_addr = get_authrpc_socket_addr(opts)?;
+    let ws_addr = get_ws_socket_addr(opts)?;
+    let jwt_secret = read_jwt_secret(&opts.authrpc_jwtsecret)?;
+    let (valid_delegation_addresses, sponsor_private_key) = match sponsor_private_key {
+        Some(sponsor_private_key) => (get_valid_delegation_addresses(opts)?, sponsor_private_key),
+        // The RPC needs a key, an unfunded one is never used since no address can be sponsored.
+        None => (Vec::new(), SecretKey::new(&mut rand::thread_rng())),
+    };
     let peer_handler = PeerHandler::new(peer_table);
 
-    // Create SyncManager
-    let syncer = SyncManager::new(
-        peer_handler.clone(),
-        opts.syncmode.clone(),
-        cancel_token,
-        blockchain.clone(),
-        store.clone(),
-    )
-    .await;
-
-    let rpc_api = ethrex_rpc::start_api(
-        get_http_socket_addr(opts),
-        get_authrpc_socket_addr(opts),
-        store,
-        blockchain,
-        read_jwtsecret_file(&opts.authrpc_jwtsecret),
-        local_p2p_node,
-        local_node_record,
-        syncer,
-        peer_handler,
-        get_client_version(),
-        get_valid_delegation_addresses(opts),
-        opts.sponsor_private_key,
-        rollup_store,
-    );
+    // The WS server dispatches calls itself, with the handles of the HTTP server.
+    let ws_context = RpcApiContext {
+        storage: store.clone(),
+        blockchain: blockchain.clone(),
+        active_filters: Default::default(),
+        syncer: Arc::new(syncer.clone()),
+        peer_handler: peer_handler.clone(),
+        node_data: NodeData {
+            jwt_secret: jwt_secret.clone().into(),
+            local_p2p_node: local_p2p_node.clone(),
+            local_node_record: local_node_record.clone(),
+            client_version: get_client_version(),
+        },
+        gas_tip_estimator: Arc::new(Mutex::new(GasTipEstimator::new())),
+        valid_delegation_addresses: valid_delegation_addresses.clone(),
+        sponsor_pk: sponsor_private_key,
+        rollup_store: rollup_store.clone(),
+    };
+    subsystems.spawn_with_restart(Subsystem::Rpc, move |cancel_token| {
+        ws::start_ws_api(ws_addr, ws_context.clone(), cancel_token)
+    });
+
+    subsystems.spawn_with_restart(Subsystem::Rpc, move |_| {
+        let peer_handler = peer_handler.clone();
+        let syncer = syncer.clone();
+        let store = store.clone();
+        let blockchain = blockchain.clone();
+        let jwt_secret = jwt_secret.clone();
+        let local_p2p_node = local_p2p_node.clone();
+        let local_node_record = local_node_record.clone();
+        let valid_delegation_addresses = valid_delegation_addresses.clone();
+        let rollup_store = rollup_store.clone();
+
+        async move {
+            ethrex_rpc::start_api(
+                http_addr,
+                authrpc_addr,
+                store,
+                blockchain,
+                jwt_secret.into(),
+                local_p2p_node,
+                local_node_record,
+                syncer,
+                peer_handler,
+                get_client_version(),
+                valid_delegation_addresses,
+                sponsor_private_key,
+                rollup_store,
+            )
+            .await
+        }
+    });
 
-    tracker.spawn(rpc_api);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mojave_chain_utils::TestDir;
+
+    use super::*;
+
+    #[cfg(unix)]
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn generated_node_key_is_reloaded() {
+        let dir = TestDir::new("node-key");
+        let datadir = Datadir::Disk(dir.path().to_owned());
+
+        let key = load_node_key(&datadir).unwrap().to_bytes();
+        assert_eq!(load_node_key(&datadir).unwrap().to_bytes(), key);
+        #[cfg(unix)]
+        assert_eq!(mode(&datadir.node_key().unwrap()), 0o600);
+    }
+
+    #[test]
+    fn generated_jwt_secret_is_reloaded() {
+        let dir = TestDir::new("jwt-secret");
+        let path = dir.path().join("jwt.hex");
+        let path = path.to_str().unwrap();
+
+        let secret = read_jwt_secret(path).unwrap();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(read_jwt_secret(path).unwrap(), secret);
+        #[cfg(unix)]
+        assert_eq!(mode(Path::new(path)), 0o600);
+    }
 }
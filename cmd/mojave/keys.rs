@@ -0,0 +1,373 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use clap::{parser::ValueSource, ArgMatches, Parser, Subcommand};
+use ethrex::utils::parse_private_key;
+use ethrex_common::Address;
+use keccak_hash::keccak;
+use mojave_chain_utils::Datadir;
+use secp256k1::SecretKey;
+
+use crate::{error::NodeError, networks::Network, options::DatadirOptions};
+
+/// Key args that can be loaded from a keystore, with the prefix of their keystore args.
+/// `--<prefix>.keystore` and `--<prefix>.password-file` replace the raw key arg.
+const KEY_ARGS: [(&str, &str); 3] = [
+    ("sponsor-private-key", "sponsor"),
+    ("committer.l1-private-key", "committer"),
+    ("proof-coordinator.l1-private-key", "proof-coordinator"),
+];
+
+#[derive(Parser, Debug, Clone, Default)]
+pub struct SequencerKeystoreOptions {
+    #[arg(
+        long = "committer.keystore",
+        value_name = "KEYSTORE",
+        help = "Keystore with the L1 committer key, used instead of `--committer.l1-private-key`.",
+        long_help = "Either a keystore file path, or the address or file name of a key in the datadir keystore.",
+        requires = "committer_password_file",
+        help_heading = "Keystore options"
+    )]
+    pub committer_keystore: Option<String>,
+    #[arg(
+        long = "committer.password-file",
+        value_name = "PASSWORD_FILE",
+        help = "File with the password of the committer keystore.",
+        help_heading = "Keystore options"
+    )]
+    pub committer_password_file: Option<PathBuf>,
+    #[arg(
+        long = "proof-coordinator.keystore",
+        value_name = "KEYSTORE",
+        help = "Keystore with the L1 proof coordinator key, used instead of `--proof-coordinator.l1-private-key`.",
+        long_help = "Either a keystore file path, or the address or file name of a key in the datadir keystore.",
+        requires = "proof_coordinator_password_file",
+        help_heading = "Keystore options"
+    )]
+    pub proof_coordinator_keystore: Option<String>,
+    #[arg(
+        long = "proof-coordinator.password-file",
+        value_name = "PASSWORD_FILE",
+        help = "File with the password of the proof coordinator keystore.",
+        help_heading = "Keystore options"
+    )]
+    pub proof_coordinator_password_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeysCommand {
+    #[command(name = "new", about = "Generate a new key in the keystore")]
+    New {
+        #[arg(
+            long = "password-file",
+            value_name = "PASSWORD_FILE",
+            help = "File with the password used to encrypt the key."
+        )]
+        password_file: PathBuf,
+    },
+    #[command(
+        name = "import",
+        about = "Encrypt an existing private key into the keystore"
+    )]
+    Import {
+        #[arg(
+            value_name = "KEY_FILE",
+            help = "File with the hex encoded private key, `-` reads it from stdin."
+        )]
+        key_file: PathBuf,
+        #[arg(
+            long = "password-file",
+            value_name = "PASSWORD_FILE",
+            help = "File with the password used to encrypt the key."
+        )]
+        password_file: PathBuf,
+    },
+    #[command(name = "list", about = "List the keys in the keystore")]
+    List,
+    #[command(name = "export", about = "Print the private key of a keystore file")]
+    Export {
+        #[arg(
+            value_name = "KEYSTORE",
+            help = "Keystore file path, or the address or file name of a key in the keystore."
+        )]
+        keystore: String,
+        #[arg(
+            long = "password-file",
+            value_name = "PASSWORD_FILE",
+            help = "File with the password of the keystore."
+        )]
+        password_file: PathBuf,
+    },
+}
+
+impl KeysCommand {
+    pub fn run(self, datadir: &Datadir) -> Result<(), NodeError> {
+        let keystore_dir = keystore_dir(datadir)?;
+        match self {
+            KeysCommand::New { password_file } => {
+                let password = read_password(&password_file)?;
+                let private_key = SecretKey::new(&mut rand::thread_rng());
+                let path = store_key(&keystore_dir, &private_key, &password)?;
+                println!("{:#x} {}", address_from_key(&private_key), path.display());
+                Ok(())
+            }
+            KeysCommand::Import {
+                key_file,
+                password_file,
+            } => {
+                let mut key = String::new();
+                if key_file == Path::new("-") {
+                    io::stdin().read_to_string(&mut key)
+                } else {
+                    fs::File::open(&key_file).and_then(|mut file| file.read_to_string(&mut key))
+                }
+                .map_err(|e| NodeError::Config(format!("Failed to read key {key_file:?}: {e}")))?;
+                let private_key = parse_private_key(key.trim())
+                    .map_err(|e| NodeError::Config(format!("Invalid private key: {e}")))?;
+                let password = read_password(&password_file)?;
+                let path = store_key(&keystore_dir, &private_key, &password)?;
+                println!("{:#x} {}", address_from_key(&private_key), path.display());
+                Ok(())
+            }
+            KeysCommand::List => {
+                for (address, path) in list_keys(&keystore_dir)? {
+                    println!("{address} {}", path.display());
+                }
+                Ok(())
+            }
+            KeysCommand::Export {
+                keystore,
+                password_file,
+            } => {
+                let private_key = decrypt_key(datadir, &keystore, &password_file)?;
+                println!("0x{}", private_key.display_secret());
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn address_from_key(private_key: &SecretKey) -> Address {
+    let public_key = private_key.public_key(secp256k1::SECP256K1);
+    let hash = keccak(&public_key.serialize_uncompressed()[1..]);
+    Address::from_slice(&hash.as_bytes()[12..])
+}
+
+fn keystore_dir(datadir: &Datadir) -> Result<PathBuf, NodeError> {
+    datadir
+        .keystore()
+        .ok_or_else(|| NodeError::Config("An in-memory datadir has no keystore".to_owned()))
+}
+
+fn read_password(path: &Path) -> Result<String, NodeError> {
+    let password = fs::read_to_string(path)
+        .map_err(|e| NodeError::Config(format!("Failed to read password file {path:?}: {e}")))?;
+    // Editors and `echo` leave a trailing newline that isn't part of the password.
+    Ok(password.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// Writes `private_key` as a geth v3 keystore file and returns its path.
+fn store_key(
+    keystore_dir: &Path,
+    private_key: &SecretKey,
+    password: &str,
+) -> Result<PathBuf, NodeError> {
+    let keystore_error = |e: String| NodeError::Config(format!("Failed to store key: {e}"));
+
+    fs::create_dir_all(keystore_dir).map_err(|e| keystore_error(e.to_string()))?;
+    let name = eth_keystore::encrypt_key(
+        keystore_dir,
+        &mut rand::thread_rng(),
+        private_key.secret_bytes(),
+        password,
+        None,
+    )
+    .map_err(|e| keystore_error(e.to_string()))?;
+
+    // geth keystores carry the address so keys can be listed without their password.
+    let path = keystore_dir.join(name);
+    let mut keystore: serde_json::Value = fs::read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| serde_json::from_str(&contents).map_err(|e| e.to_string()))
+        .map_err(keystore_error)?;
+    keystore["address"] = format!("{:x}", address_from_key(private_key)).into();
+    fs::write(&path, keystore.to_string()).map_err(|e| keystore_error(e.to_string()))?;
+
+    Ok(path)
+}
+
+/// Addresses and paths of the keystore files in `keystore_dir`.
+fn list_keys(keystore_dir: &Path) -> Result<Vec<(String, PathBuf)>, NodeError> {
+    let entries = match fs::read_dir(keystore_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(NodeError::Config(format!(
+                "Failed to read keystore {keystore_dir:?}: {e}"
+            )))
+        }
+    };
+
+    let mut keys = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let address = fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<serde_json::Value>(&contents).ok())
+            .and_then(|keystore| keystore["address"].as_str().map(str::to_owned));
+        match address {
+            Some(address) => keys.push((format!("0x{}", address.trim_start_matches("0x")), path)),
+            None => tracing::debug!("Skipping {:?}, not a keystore file", path),
+        }
+    }
+    keys.sort();
+    Ok(keys)
+}
+
+/// Resolves a keystore reference: a file path, or the address or file name of a key in the
+/// keystore of `datadir`.
+fn find_keystore(datadir: &Datadir, reference: &str) -> Result<PathBuf, NodeError> {
+    let path = PathBuf::from(reference);
+    if path.is_file() {
+        return Ok(path);
+    }
+    let keystore_dir = keystore_dir(datadir)?;
+    let in_dir = keystore_dir.join(reference);
+    if in_dir.is_file() {
+        return Ok(in_dir);
+    }
+
+    let address = reference.trim_start_matches("0x").to_lowercase();
+    list_keys(&keystore_dir)?
+        .into_iter()
+        .find(|(key_address, _)| key_address.trim_start_matches("0x").to_lowercase() == address)
+        .map(|(_, path)| path)
+        .ok_or_else(|| {
+            NodeError::Config(format!(
+                "No keystore {reference} as a file or in {keystore_dir:?}"
+            ))
+        })
+}
+
+fn decrypt_key(
+    datadir: &Datadir,
+    reference: &str,
+    password_file: &Path,
+) -> Result<SecretKey, NodeError> {
+    let path = find_keystore(datadir, reference)?;
+    let password = read_password(password_file)?;
+    let key = eth_keystore::decrypt_key(&path, password)
+        .map_err(|e| NodeError::Config(format!("Failed to decrypt keystore {path:?}: {e}")))?;
+    SecretKey::from_slice(&key)
+        .map_err(|e| NodeError::Config(format!("Invalid key in keystore {path:?}: {e}")))
+}
+
+/// Loads the keys given as `--<prefix>.keystore` and sets them as the default of their raw key
+/// arg, so the node options are parsed as if the key was passed directly but it never shows up
+/// in the process args.
+pub fn apply_keystores(
+    command: clap::Command,
+    args: &[OsString],
+) -> Result<clap::Command, NodeError> {
+    // Raw key args may be required, they are relaxed to find the keystore args first.
+    let mut relaxed = command.clone();
+    for name in subcommand_names(&relaxed) {
+        relaxed = relaxed.mut_subcommand(name, |mut subcommand| {
+            for id in key_arg_ids(&subcommand) {
+                subcommand = subcommand.mut_arg(id, |arg| arg.required(false));
+            }
+            subcommand
+        });
+    }
+    // Invalid args are reported by the real parse.
+    let Ok(matches) = relaxed.try_get_matches_from(args) else {
+        return Ok(command);
+    };
+    let Some((name, sub_matches)) = matches.subcommand() else {
+        return Ok(command);
+    };
+    let Some(subcommand) = command.find_subcommand(name) else {
+        return Ok(command);
+    };
+
+    let datadir_opts = DatadirOptions {
+        datadir: get_string(subcommand, sub_matches, "datadir"),
+        network: get_string(subcommand, sub_matches, "network")
+            .map(|network| Network::from(network.as_str())),
+    };
+
+    let mut keys = Vec::new();
+    for (key_long, prefix) in KEY_ARGS {
+        let Some(key_id) = arg_id(subcommand, key_long) else {
+            continue;
+        };
+        let Some(reference) = get_string(subcommand, sub_matches, &format!("{prefix}.keystore"))
+        else {
+            continue;
+        };
+        if matches!(
+            sub_matches.value_source(&key_id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            return Err(NodeError::Config(format!(
+                "Both `--{key_long}` and `--{prefix}.keystore` are set, use only one of them"
+            )));
+        }
+        let password_file = get_string(subcommand, sub_matches, &format!("{prefix}.password-file"))
+            .ok_or_else(|| {
+                NodeError::Config(format!(
+                    "`--{prefix}.keystore` needs `--{prefix}.password-file`"
+                ))
+            })?;
+        let private_key = decrypt_key(
+            &datadir_opts.datadir()?,
+            &reference,
+            Path::new(&password_file),
+        )?;
+        keys.push((key_id, format!("0x{}", private_key.display_secret())));
+    }
+
+    Ok(command.mut_subcommand(name, |mut subcommand| {
+        for (id, key) in keys {
+            subcommand = subcommand.mut_arg(id, |arg| {
+                arg.required(false)
+                    .default_value(key)
+                    .hide_default_value(true)
+            });
+        }
+        subcommand
+    }))
+}
+
+fn subcommand_names(command: &clap::Command) -> Vec<String> {
+    command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_owned())
+        .collect()
+}
+
+fn key_arg_ids(command: &clap::Command) -> Vec<String> {
+    KEY_ARGS
+        .iter()
+        .filter_map(|(key_long, _)| arg_id(command, key_long))
+        .collect()
+}
+
+fn arg_id(command: &clap::Command, long: &str) -> Option<String> {
+    command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(long))
+        .map(|arg| arg.get_id().as_str().to_owned())
+}
+
+fn get_string(command: &clap::Command, matches: &ArgMatches, long: &str) -> Option<String> {
+    let id = arg_id(command, long)?;
+    matches
+        .get_raw(&id)?
+        .next()
+        .map(|value| value.to_string_lossy().into_owned())
+}
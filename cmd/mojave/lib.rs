@@ -1,9 +1,22 @@
+pub mod abci;
+pub mod block_file;
 pub mod cli;
 pub mod command;
+pub mod config;
+pub mod db;
+#[cfg(feature = "dev")]
+pub mod dev;
+pub mod error;
+pub mod follower;
+pub mod genesis;
+pub mod init;
 pub mod initializer;
+pub mod keys;
 pub mod logging;
 pub mod networks;
 pub mod options;
+pub mod shutdown;
+pub mod state_dump;
+pub mod subsystem;
 pub(crate) mod version;
-
-pub const DEFAULT_DATADIR: &str = "mojave";
+pub mod ws;
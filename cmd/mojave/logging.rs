@@ -1,12 +1,92 @@
-use tracing::Level;
-use tracing_subscriber::{filter::Directive, EnvFilter, FmtSubscriber};
-
-pub fn init_logging(log_level: Level) {
-    let log_filter = EnvFilter::builder()
-        .with_default_directive(Directive::from(log_level))
-        .from_env_lossy();
-    let subscriber = FmtSubscriber::builder()
-        .with_env_filter(log_filter)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+use std::{fs, io};
+
+use clap::ValueEnum;
+use rolling_file::{BasicRollingFileAppender, RollingConditionBasic};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter, FmtSubscriber};
+
+use crate::{error::NodeError, options::LogOptions};
+
+/// Level of the targets without a directive in `--log.level`.
+const DEFAULT_LEVEL: &str = "info";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines with the span context.
+    Text,
+    /// One JSON object per line.
+    Json,
+    /// Shorter human readable lines.
+    Compact,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogRotation {
+    Daily,
+    Hourly,
+    /// Only rotate on `--log.max-size`.
+    Never,
+}
+
+/// Checks a `--log.level` value, a level or comma separated `target=level` directives.
+pub fn parse_log_filter(filter: &str) -> Result<String, String> {
+    EnvFilter::builder()
+        .parse(filter)
+        .map(|_| filter.to_owned())
+        .map_err(|e| e.to_string())
+}
+
+/// Installs the global subscriber, writing to stdout or to the rotated `--log.file`. File logs
+/// go through a background writer, the returned guard flushes it when dropped and has to be
+/// kept until the process exits.
+pub fn init_logging(opts: &LogOptions) -> Result<Option<WorkerGuard>, NodeError> {
+    // Later directives win, a level in `--log.level` replaces the default one.
+    let filter = EnvFilter::builder().parse_lossy(format!("{DEFAULT_LEVEL},{}", opts.log_level));
+
+    let Some(path) = &opts.log_file else {
+        set_subscriber(opts.log_format, filter, io::stdout, true);
+        return Ok(None);
+    };
+
+    let open_error =
+        |e: io::Error| NodeError::Config(format!("Failed to open log file {path:?}: {e}"));
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(open_error)?;
+    }
+    let condition = match opts.log_rotation {
+        LogRotation::Daily => RollingConditionBasic::new().daily(),
+        LogRotation::Hourly => RollingConditionBasic::new().hourly(),
+        LogRotation::Never => RollingConditionBasic::new(),
+    };
+    let condition = match opts.log_max_size {
+        0 => condition,
+        megabytes => condition.max_size(megabytes.saturating_mul(1024 * 1024)),
+    };
+    let appender =
+        BasicRollingFileAppender::new(path, condition, opts.log_max_files).map_err(open_error)?;
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+
+    set_subscriber(opts.log_format, filter, writer, false);
+    Ok(Some(guard))
+}
+
+fn set_subscriber<W>(format: LogFormat, filter: EnvFilter, writer: W, ansi: bool)
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let builder = FmtSubscriber::builder()
+        .with_env_filter(filter)
+        .with_writer(writer);
+    // Color codes have no place in a file, stdout keeps the default.
+    let builder = if ansi {
+        builder
+    } else {
+        builder.with_ansi(false)
+    };
+    let result = match format {
+        LogFormat::Text => tracing::subscriber::set_global_default(builder.finish()),
+        LogFormat::Json => tracing::subscriber::set_global_default(builder.json().finish()),
+        LogFormat::Compact => tracing::subscriber::set_global_default(builder.compact().finish()),
+    };
+    result.expect("setting default subscriber failed");
 }
@@ -1,16 +1,35 @@
-use anyhow::Result;
-use clap::Parser;
+use std::process::ExitCode;
+
 use mojave::{cli::CLI, logging::init_logging};
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let CLI { log_level, command } = CLI::parse();
+async fn main() -> ExitCode {
+    let CLI {
+        log_opts, command, ..
+    } = match CLI::parse_with_config() {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(e.exit_code());
+        }
+    };
 
-    init_logging(log_level);
+    // Flushes the log file on exit.
+    let _log_guard = match init_logging(&log_opts) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::from(e.exit_code());
+        }
+    };
 
     tracing::debug!( command = ?command, "Starting Mojave node");
 
-    command.run().await?;
-
-    Ok(())
+    match command.run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            tracing::error!("{e}");
+            ExitCode::from(e.exit_code())
+        }
+    }
 }
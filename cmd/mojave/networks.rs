@@ -3,70 +3,53 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use ethrex_common::types::{Genesis, GenesisError};
-use ethrex_p2p::types::Node;
-use lazy_static::lazy_static;
+use ethrex_common::types::Genesis;
+use mojave_chain_utils::Datadir;
 
-pub const TESTNET_GENESIS_PATH: &str = "cmd/mojave/networks/testnet/genesis.json";
-const TESTNET_BOOTNODES_PATH: &str = "cmd/mojave/networks/testnet/bootnodes.json";
+use crate::error::NodeError;
 
-pub const MAINNET_GENESIS_PATH: &str = "cmd/mojave/networks/mainnet/genesis.json";
-const MAINNET_BOOTNODES_PATH: &str = "cmd/mojave/networks/mainnet/bootnodes.json";
-
-lazy_static! {
-    pub static ref MAINNET_BOOTNODES: Vec<Node> = serde_json::from_reader(
-        std::fs::File::open(MAINNET_BOOTNODES_PATH).expect("Failed to open mainnet bootnodes file")
-    )
-    .expect("Failed to parse mainnet bootnodes file");
-    pub static ref TESTNET_BOOTNODES: Vec<Node> = serde_json::from_reader(
-        std::fs::File::open(TESTNET_BOOTNODES_PATH).expect("Failed to open testnet bootnodes file")
-    )
-    .expect("Failed to parse testnet bootnodes file");
-}
-
-#[derive(Debug, Clone, Default)]
-pub enum Network {
-    #[default]
-    Mainnet,
-    Testnet,
-    GenesisPath(PathBuf),
-}
+/// Chain of a node, given by its genesis file. Mojave doesn't ship the genesis of a public
+/// network yet, so there are no named networks and `--network` is a required path.
+#[derive(Debug, Clone)]
+pub struct Network(PathBuf);
 
 impl From<&str> for Network {
     fn from(value: &str) -> Self {
-        match value {
-            "mainnet" => Network::Mainnet,
-            "testnet" => Network::Testnet,
-            s => Network::GenesisPath(PathBuf::from(s)),
-        }
+        Network(PathBuf::from(value))
     }
 }
 
 impl From<PathBuf> for Network {
     fn from(value: PathBuf) -> Self {
-        Network::GenesisPath(value)
+        Network(value)
     }
 }
 
 impl Network {
     pub fn get_genesis_path(&self) -> &Path {
-        match self {
-            Network::Mainnet => Path::new(MAINNET_GENESIS_PATH),
-            Network::Testnet => Path::new(TESTNET_GENESIS_PATH),
-            Network::GenesisPath(s) => s,
-        }
+        &self.0
     }
-    pub fn get_genesis(&self) -> Result<Genesis, GenesisError> {
-        Genesis::try_from(self.get_genesis_path())
+
+    pub fn get_genesis(&self) -> Result<Genesis, NodeError> {
+        Ok(Genesis::try_from(self.get_genesis_path())?)
+    }
+
+    /// Name of the default datadir of the network, the genesis file name without its extension.
+    pub fn name(&self) -> String {
+        self.0.file_stem().map_or_else(
+            || "custom".to_owned(),
+            |stem| stem.to_string_lossy().into_owned(),
+        )
+    }
+
+    /// Resolves `--datadir`, defaulting to a directory named after the genesis file.
+    pub fn datadir(&self, datadir: Option<&str>) -> Result<Datadir, NodeError> {
+        Datadir::resolve(datadir, &self.name()).map_err(|e| NodeError::Config(e.to_string()))
     }
 }
 
 impl fmt::Display for Network {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Network::Mainnet => write!(f, "Mainnet"),
-            Network::Testnet => write!(f, "Testnet"),
-            Network::GenesisPath(path) => write!(f, "{path:?}"),
-        }
+        write!(f, "{}", self.0.display())
     }
 }
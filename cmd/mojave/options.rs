@@ -1,12 +1,18 @@
 use anyhow::anyhow;
 use clap::{ArgAction, Parser};
-use ethrex::{l2::SequencerOptions, utils};
+use ethrex::utils;
+use ethrex_common::Address;
 use ethrex_p2p::{sync::SyncMode, types::Node};
 use ethrex_vm::EvmEngine;
+use mojave_chain_utils::Datadir;
 use secp256k1::SecretKey;
-use std::fmt;
+use std::{fmt, net::SocketAddr, path::PathBuf};
 
-use crate::{networks::Network, DEFAULT_DATADIR};
+use crate::{
+    error::NodeError,
+    logging::{parse_log_filter, LogFormat, LogRotation},
+    networks::Network,
+};
 
 pub fn parse_evm_level(s: &str) -> anyhow::Result<EvmEngine> {
     EvmEngine::try_from(s.to_owned()).map_err(|e| anyhow!(e))
@@ -14,6 +20,8 @@ pub fn parse_evm_level(s: &str) -> anyhow::Result<EvmEngine> {
 
 #[derive(Parser)]
 pub struct Options {
+    #[command(flatten)]
+    pub datadir_opts: DatadirOptions,
     #[arg(
         long = "ws.port",
         default_value_t = 8546,
@@ -30,19 +38,15 @@ pub struct Options {
         help_heading = "Node options"
     )]
     pub ws_host: String,
-    #[arg(
-        long = "network",
-        default_value_t = Network::default(),
-        value_name = "GENESIS_FILE_PATH",
-        help = "Receives a `Genesis` struct in json format. This is the only argument which is required. You can look at some example genesis files at `test_data/genesis*`.",
-        long_help = "Alternatively, the name of a known network can be provided instead to use its preset genesis file and include its preset bootnodes. The networks currently supported include holesky, sepolia, hoodi and mainnet.",
-        help_heading = "Node options",
-        env = "ETHREX_NETWORK",
-        value_parser = clap::value_parser!(Network),
-    )]
-    pub network: Network,
     #[arg(long = "bootnodes", value_parser = clap::value_parser!(Node), value_name = "BOOTNODE_LIST", value_delimiter = ',', num_args = 1.., help = "Comma separated enode URLs for P2P discovery bootstrap.", help_heading = "P2P options")]
     pub bootnodes: Vec<Node>,
+    #[arg(
+        long = "bootnodes.file",
+        value_name = "BOOTNODES_FILE_PATH",
+        help = "JSON file with a list of enode URLs added to the bootnodes.",
+        help_heading = "P2P options"
+    )]
+    pub bootnodes_file: Option<PathBuf>,
     #[arg(long = "syncmode", default_value = "full", value_name = "SYNC_MODE", value_parser = utils::parse_sync_mode, help = "The way in which the node will sync its state.", long_help = "Can be either \"full\" or \"snap\" with \"full\" as default value.", help_heading = "P2P options")]
     pub syncmode: SyncMode,
     #[arg(
@@ -52,28 +56,33 @@ pub struct Options {
         help_heading = "L2 options"
     )]
     pub sponsorable_addresses_file_path: Option<String>,
-    //TODO: make optional when the the sponsored feature is complete
-    #[arg(long, default_value = "0xffd790338a2798b648806fc8635ac7bf14af15425fed0c8f25bcc5febaa9b192", value_parser = utils::parse_private_key, env = "SPONSOR_PRIVATE_KEY", help = "The private key of ethrex L2 transactions sponsor.", help_heading = "L2 options")]
-    pub sponsor_private_key: SecretKey,
+    #[arg(long, value_parser = utils::parse_private_key, env = "SPONSOR_PRIVATE_KEY", help = "The private key of ethrex L2 transactions sponsor.", long_help = "Sponsored transactions are disabled when no key is given. The public development key is refused outside of `--dev` and the development genesis (chain id 1729).", help_heading = "L2 options")]
+    pub sponsor_private_key: Option<SecretKey>,
     #[arg(
-        long = "datadir",
-        value_name = "DATABASE_DIRECTORY",
-        help = "If the datadir is the word `memory`, ethrex will use the InMemory Engine",
-        default_value = DEFAULT_DATADIR,
-        help = "Receives the name of the directory where the Database is located.",
-        long_help = "If the datadir is the word `memory`, ethrex will use the `InMemory Engine`.",
-        help_heading = "Node options",
-        env = "ETHREX_DATADIR"
+        long = "sponsor.keystore",
+        value_name = "KEYSTORE",
+        help = "Keystore with the sponsor key, used instead of `--sponsor-private-key`.",
+        long_help = "Either a keystore file path, or the address or file name of a key in the datadir keystore.",
+        requires = "sponsor_password_file",
+        help_heading = "Keystore options"
+    )]
+    pub sponsor_keystore: Option<String>,
+    #[arg(
+        long = "sponsor.password-file",
+        value_name = "PASSWORD_FILE",
+        help = "File with the password of the sponsor keystore.",
+        help_heading = "Keystore options"
     )]
-    pub datadir: String,
+    pub sponsor_password_file: Option<PathBuf>,
     #[arg(
-        long = "force",
-        help = "Force remove the database",
-        long_help = "Delete the database without confirmation.",
-        action = clap::ArgAction::SetTrue,
+        long = "shutdown.timeout",
+        default_value_t = 10,
+        value_name = "SECONDS",
+        help = "Maximum time to wait for each subsystem to stop on shutdown.",
+        long_help = "Subsystems get this long to finish their in-flight work once cancelled, then their tasks are aborted.",
         help_heading = "Node options"
     )]
-    pub force: bool,
+    pub shutdown_timeout: u64,
     #[arg(
         long = "metrics.addr",
         value_name = "ADDRESS",
@@ -100,10 +109,26 @@ pub struct Options {
         long = "dev",
         action = ArgAction::SetTrue,
         help = "Used to create blocks without requiring a Consensus Client",
-        long_help = "If set it will be considered as `true`. The Binary has to be built with the `dev` feature enabled.",
-        help_heading = "Node options"
+        long_help = "Runs a local development chain: in-memory database, P2P disabled, blocks produced by the node itself and prefunded test accounts printed on startup. The Binary has to be built with the `dev` feature enabled.",
+        help_heading = "Dev options"
     )]
     pub dev: bool,
+    #[arg(
+        long = "dev.block-time",
+        value_name = "MILLISECONDS",
+        help = "Produce a block at this interval instead of one block per transaction.",
+        requires = "dev",
+        help_heading = "Dev options"
+    )]
+    pub dev_block_time: Option<u64>,
+    #[arg(
+        long = "dev.accounts",
+        default_value_t = 10,
+        value_name = "COUNT",
+        help = "Number of prefunded test accounts.",
+        help_heading = "Dev options"
+    )]
+    pub dev_accounts: usize,
     #[arg(
         long = "evm",
         default_value_t = EvmEngine::default(),
@@ -187,57 +212,23 @@ pub struct Options {
         help_heading = "P2P options"
     )]
     pub discovery_port: String,
-    #[command(flatten)]
-    pub sequencer_opts: SequencerOptions,
-}
-
-impl Default for Options {
-    fn default() -> Self {
-        Self {
-            http_addr: Default::default(),
-            http_port: Default::default(),
-            authrpc_addr: Default::default(),
-            authrpc_port: Default::default(),
-            authrpc_jwtsecret: Default::default(),
-            p2p_enabled: Default::default(),
-            p2p_addr: Default::default(),
-            p2p_port: Default::default(),
-            discovery_addr: Default::default(),
-            discovery_port: Default::default(),
-            network: Network::Mainnet,
-            bootnodes: Default::default(),
-            datadir: Default::default(),
-            syncmode: Default::default(),
-            sponsorable_addresses_file_path: None,
-            sponsor_private_key: utils::parse_private_key(
-                "0xffd790338a2798b648806fc8635ac7bf14af15425fed0c8f25bcc5febaa9b192",
-            )
-            .unwrap(),
-            metrics_addr: "0.0.0.0".to_owned(),
-            metrics_port: Default::default(),
-            metrics_enabled: Default::default(),
-            dev: Default::default(),
-            evm: Default::default(),
-            force: false,
-            ws_port: 8546,
-            ws_host: "0.0.0.0".to_string(),
-            sequencer_opts: SequencerOptions::default(),
-        }
-    }
 }
 
 impl fmt::Debug for Options {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Options")
-            .field("network", &self.network)
+            .field("datadir_opts", &self.datadir_opts)
             .field("bootnodes", &self.bootnodes)
-            .field("datadir", &self.datadir)
-            .field("force", &self.force)
+            .field("bootnodes_file", &self.bootnodes_file)
+            .field("sponsor_keystore", &self.sponsor_keystore)
+            .field("shutdown_timeout", &self.shutdown_timeout)
             .field("syncmode", &self.syncmode)
             .field("metrics_addr", &self.metrics_addr)
             .field("metrics_port", &self.metrics_port)
             .field("metrics_enabled", &self.metrics_enabled)
             .field("dev", &self.dev)
+            .field("dev_block_time", &self.dev_block_time)
+            .field("dev_accounts", &self.dev_accounts)
             .field("evm", &self.evm)
             .field("http_addr", &self.http_addr)
             .field("http_port", &self.http_port)
@@ -254,3 +245,188 @@ impl fmt::Debug for Options {
             .finish()
     }
 }
+
+/// Datadir and genesis of the node, and of the commands that work on a stopped node. `global`
+/// so they can be given after the subcommands of `mojave keys` and `mojave db`.
+#[derive(Parser, Debug, Clone)]
+pub struct DatadirOptions {
+    #[arg(
+        long = "datadir",
+        value_name = "DATABASE_DIRECTORY",
+        help = "Directory of the database and node files, `memory` for the in-memory engine.",
+        long_help = "Defaults to `$XDG_DATA_HOME/mojave/<network>` (`~/.local/share` without `XDG_DATA_HOME`). A leading `~` is the home directory. If the datadir is the word `memory`, ethrex will use the `InMemory Engine`.",
+        env = "ETHREX_DATADIR",
+        global = true
+    )]
+    pub datadir: Option<String>,
+    #[arg(
+        long = "network",
+        value_name = "GENESIS_FILE_PATH",
+        value_parser = clap::value_parser!(Network),
+        help = "Genesis file of the chain, in json format. See `test_data/genesis*` for examples.",
+        long_help = "Mojave doesn't ship the genesis of a public network yet, the genesis file of the chain has to be given. Also picks the default datadir when `--datadir` isn't set. Required by the node and the commands that need the genesis.",
+        env = "ETHREX_NETWORK",
+        global = true
+    )]
+    pub network: Option<Network>,
+}
+
+impl DatadirOptions {
+    /// `--network`, which can't be required by clap as it's global.
+    pub fn network(&self) -> Result<&Network, NodeError> {
+        self.network.as_ref().ok_or_else(|| {
+            NodeError::Config("`--network <GENESIS_FILE_PATH>` is required".to_owned())
+        })
+    }
+
+    /// Resolves `--datadir`, which only needs `--network` when it isn't set.
+    pub fn datadir(&self) -> Result<Datadir, NodeError> {
+        match (&self.network, self.datadir.as_deref()) {
+            (Some(network), datadir) => network.datadir(datadir),
+            (None, Some(datadir)) => {
+                Datadir::resolve(Some(datadir), "").map_err(|e| NodeError::Config(e.to_string()))
+            }
+            (None, None) => Err(NodeError::Config(
+                "Either `--datadir` or `--network` is required to find the datadir".to_owned(),
+            )),
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct FollowerOptions {
+    #[arg(
+        long = "sequencer.url",
+        value_name = "URL",
+        help = "HTTP RPC endpoint of the sequencer this node follows.",
+        long_help = "The node polls the sequencer head to trigger block sync from its peers and compares state roots against it. The sequencer is also added to the bootnodes.",
+        help_heading = "Follower options",
+        env = "MOJAVE_SEQUENCER_URL"
+    )]
+    pub sequencer_url: Option<String>,
+    #[arg(
+        long = "sequencer.poll-interval",
+        default_value_t = 1000,
+        value_name = "MILLISECONDS",
+        help = "How often the sequencer head is polled.",
+        help_heading = "Follower options"
+    )]
+    pub poll_interval_ms: u64,
+}
+
+impl Default for FollowerOptions {
+    fn default() -> Self {
+        Self {
+            sequencer_url: None,
+            poll_interval_ms: 1000,
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct AbciOptions {
+    #[arg(
+        long = "abci.addr",
+        default_value = "127.0.0.1:26658",
+        value_name = "ADDRESS",
+        help = "Address the ABCI server listens on for CometBFT connections.",
+        long_help = "Must match the `proxy_app` setting of the CometBFT `config.toml`.",
+        help_heading = "ABCI options",
+        env = "MOJAVE_ABCI_ADDR"
+    )]
+    pub abci_addr: SocketAddr,
+    #[arg(
+        long = "abci.coinbase-address",
+        default_value = "0x0000000000000000000000000000000000000000",
+        value_name = "ADDRESS",
+        help = "Fee recipient of the blocks proposed by this validator.",
+        help_heading = "ABCI options"
+    )]
+    pub coinbase_address: Address,
+    #[arg(
+        long = "abci.proposal-record",
+        value_name = "FILE",
+        help = "File with the last block proposed by this validator.",
+        long_help = "A height that was already proposed gets the recorded block again, and lower heights are refused. Defaults to `mojave_proposal.json` in the datadir. This doesn't replace the double-sign protection of CometBFT, which signs with its own key. Don't point it at CometBFT's `priv_validator_state.json`.",
+        help_heading = "ABCI options"
+    )]
+    pub proposal_record: Option<PathBuf>,
+}
+
+impl Default for AbciOptions {
+    fn default() -> Self {
+        Self {
+            abci_addr: SocketAddr::from(([127, 0, 0, 1], 26658)),
+            coinbase_address: Address::zero(),
+            proposal_record: None,
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct LogOptions {
+    #[arg(
+        long = "log.level",
+        default_value = "info",
+        value_name = "LOG_FILTER",
+        value_parser = parse_log_filter,
+        help = "The verbosity level used for logs, globally or per target.",
+        long_help = "A level (error, warn, info, debug, trace) or comma separated directives such as `ethrex_p2p=warn,ethrex_l2=debug`. Targets without a directive log at `info`.",
+        help_heading = "Log options",
+        env = "RUST_LOG"
+    )]
+    pub log_level: String,
+    #[arg(
+        long = "log.format",
+        default_value_t = LogFormat::Text,
+        value_enum,
+        help = "Format of the log lines.",
+        help_heading = "Log options"
+    )]
+    pub log_format: LogFormat,
+    #[arg(
+        long = "log.file",
+        value_name = "FILE",
+        help = "File the logs are written to instead of stdout.",
+        long_help = "Rotated files are kept next to it as `<FILE>.1` (the newest) to `<FILE>.<log.max-files>`.",
+        help_heading = "Log options"
+    )]
+    pub log_file: Option<PathBuf>,
+    #[arg(
+        long = "log.rotation",
+        default_value_t = LogRotation::Daily,
+        value_enum,
+        help = "Interval at which the log file is rotated.",
+        help_heading = "Log options"
+    )]
+    pub log_rotation: LogRotation,
+    #[arg(
+        long = "log.max-size",
+        default_value_t = 100,
+        value_name = "MEGABYTES",
+        help = "Size at which the log file is rotated, 0 for no limit.",
+        help_heading = "Log options"
+    )]
+    pub log_max_size: u64,
+    #[arg(
+        long = "log.max-files",
+        default_value_t = 7,
+        value_name = "COUNT",
+        help = "Number of rotated log files kept, older ones are deleted.",
+        help_heading = "Log options"
+    )]
+    pub log_max_files: usize,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        Self {
+            log_level: "info".to_owned(),
+            log_format: LogFormat::Text,
+            log_file: None,
+            log_rotation: LogRotation::Daily,
+            log_max_size: 100,
+            log_max_files: 7,
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::{fmt, io};
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ShutdownSignal {
+    Interrupt,
+    Terminate,
+    Hangup,
+}
+
+impl fmt::Display for ShutdownSignal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShutdownSignal::Interrupt => write!(f, "SIGINT"),
+            ShutdownSignal::Terminate => write!(f, "SIGTERM"),
+            ShutdownSignal::Hangup => write!(f, "SIGHUP"),
+        }
+    }
+}
+
+/// Process signals that trigger a node shutdown. Handlers are installed on creation so a
+/// signal received while the node is still starting is not lost.
+pub struct ShutdownSignals {
+    #[cfg(unix)]
+    terminate: Signal,
+    #[cfg(unix)]
+    hangup: Signal,
+}
+
+impl ShutdownSignals {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            terminate: signal(SignalKind::terminate())?,
+            #[cfg(unix)]
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    pub async fn recv(&mut self) -> ShutdownSignal {
+        #[cfg(unix)]
+        {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => ShutdownSignal::Interrupt,
+                _ = self.terminate.recv() => ShutdownSignal::Terminate,
+                _ = self.hangup.recv() => ShutdownSignal::Hangup,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+            ShutdownSignal::Interrupt
+        }
+    }
+}
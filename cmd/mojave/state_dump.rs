@@ -0,0 +1,432 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use ethrex_common::{types::Genesis, Address, H256};
+use ethrex_storage::Store;
+use keccak_hash::keccak;
+use mojave_chain_utils::Datadir;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    db::migrate_datadir,
+    error::NodeError,
+    initializer::{init_store, lock_datadir},
+    networks::Network,
+};
+
+/// Block whose state is dumped.
+#[derive(Clone, Copy, Debug)]
+pub enum BlockId {
+    Latest,
+    Number(u64),
+}
+
+impl FromStr for BlockId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latest" => Ok(BlockId::Latest),
+            number => number
+                .parse()
+                .map(BlockId::Number)
+                .map_err(|_| format!("expected a block number or `latest`, got `{number}`")),
+        }
+    }
+}
+
+/// Addresses and storage slots by their hash, the state trie only has the hashes as keys.
+///
+/// ethrex doesn't record preimages, so they come from the genesis alloc and from an optional
+/// table of addresses and slots. The memory used is bounded by those, not by the state or the
+/// length of the chain.
+#[derive(Default)]
+struct Preimages {
+    addresses: HashMap<H256, Address>,
+    slots: HashMap<H256, H256>,
+}
+
+impl Preimages {
+    /// Loads the addresses and slots of the genesis alloc and of the `table` file, which has
+    /// one hex encoded address or 32 byte storage slot per line.
+    fn load(genesis: &Genesis, table: Option<&Path>) -> Result<Self, NodeError> {
+        let mut preimages = Self::default();
+        for (address, account) in &genesis.alloc {
+            preimages.add_address(*address);
+            for slot in account.storage.keys() {
+                preimages.add_slot(H256(slot.to_big_endian()));
+            }
+        }
+
+        let Some(table) = table else {
+            return Ok(preimages);
+        };
+        let read_error =
+            |e: String| NodeError::Config(format!("Failed to read preimage table {table:?}: {e}"));
+        let reader = BufReader::new(File::open(table).map_err(|e| read_error(e.to_string()))?);
+        for (index, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| read_error(e.to_string()))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bytes = hex::decode(line.strip_prefix("0x").unwrap_or(line))
+                .map_err(|e| read_error(format!("line {}: {e}", index + 1)))?;
+            match bytes.len() {
+                20 => preimages.add_address(Address::from_slice(&bytes)),
+                32 => preimages.add_slot(H256::from_slice(&bytes)),
+                len => {
+                    return Err(read_error(format!(
+                        "line {}: expected a 20 byte address or a 32 byte slot, got {len} bytes",
+                        index + 1
+                    )))
+                }
+            }
+        }
+        Ok(preimages)
+    }
+
+    fn add_address(&mut self, address: Address) {
+        self.addresses.insert(hash(address), address);
+    }
+
+    fn add_slot(&mut self, slot: H256) {
+        self.slots.insert(hash(slot), slot);
+    }
+}
+
+fn hash(data: impl AsRef<[u8]>) -> H256 {
+    H256(keccak(data).0)
+}
+
+fn storage_error(e: impl std::fmt::Display) -> NodeError {
+    NodeError::Storage(e.to_string())
+}
+
+/// Writes every account of the state at `block` to `path`, one JSON object per line, as the
+/// state trie is walked.
+///
+/// Accounts are keyed by `addressHash`, with their `address` when it's known and `null`
+/// otherwise. Storage slots with a known preimage go in `storage`, the others in
+/// `hashedStorage` keyed by the slot hash. Preimages come from the genesis and `preimages`.
+pub async fn dump_state(
+    datadir: &Datadir,
+    network: &Network,
+    block: BlockId,
+    preimages: Option<&Path>,
+    path: &Path,
+) -> Result<(), NodeError> {
+    if !datadir.exists() {
+        return Err(NodeError::Config(format!(
+            "Datadir {datadir} doesn't exist, there is no state to dump"
+        )));
+    }
+    let _lock = lock_datadir(datadir)?;
+    migrate_datadir(datadir)?;
+    let genesis = network.get_genesis()?;
+    let preimages = Preimages::load(&genesis, preimages)?;
+    let store = init_store(datadir, genesis).await?;
+
+    let number = match block {
+        BlockId::Latest => store
+            .get_latest_block_number()
+            .await
+            .map_err(storage_error)?,
+        BlockId::Number(number) => number,
+    };
+    let state_root = store
+        .get_block_header(number)
+        .map_err(storage_error)?
+        .ok_or_else(|| NodeError::Config(format!("The datadir has no block {number}")))?
+        .state_root;
+
+    tracing::info!("Dumping the state of block {number} with root {state_root:#x} to {path:?}");
+    let write_error =
+        |e: io::Error| NodeError::Storage(format!("Failed to write state to {path:?}: {e}"));
+    let mut writer = BufWriter::new(File::create(path).map_err(write_error)?);
+    let empty_code_hash = hash([]);
+    let (mut accounts, mut unknown_accounts, mut unknown_slots) = (0u64, 0u64, 0u64);
+
+    for (address_hash, account) in store.iter_accounts(state_root).map_err(storage_error)? {
+        let address = preimages.addresses.get(&address_hash).copied();
+        unknown_accounts += u64::from(address.is_none());
+
+        let mut line = json!({
+            "addressHash": format!("{address_hash:#x}"),
+            "address": address.map(|address| format!("{address:#x}")),
+            "balance": format!("{:#x}", account.balance),
+            "nonce": format!("{:#x}", account.nonce),
+        });
+        if account.code_hash != empty_code_hash {
+            let code = store
+                .get_account_code(account.code_hash)
+                .map_err(storage_error)?
+                .ok_or_else(|| {
+                    NodeError::Storage(format!("Missing code {:#x}", account.code_hash))
+                })?;
+            line["code"] = format!("0x{}", hex::encode(code)).into();
+        }
+
+        let (mut storage, mut hashed_storage) = (Map::new(), Map::new());
+        let slots = store
+            .iter_storage(state_root, address_hash)
+            .map_err(storage_error)?;
+        for (slot_hash, value) in slots.into_iter().flatten() {
+            let value = Value::from(format!("{value:#x}"));
+            match preimages.slots.get(&slot_hash) {
+                Some(slot) => storage.insert(format!("{slot:#x}"), value),
+                None => {
+                    unknown_slots += 1;
+                    hashed_storage.insert(format!("{slot_hash:#x}"), value)
+                }
+            };
+        }
+        if !storage.is_empty() {
+            line["storage"] = storage.into();
+        }
+        if !hashed_storage.is_empty() {
+            line["hashedStorage"] = hashed_storage.into();
+        }
+
+        writeln!(writer, "{line}").map_err(write_error)?;
+        accounts += 1;
+    }
+    writer.flush().map_err(write_error)?;
+
+    tracing::info!("Dumped {accounts} accounts of block {number} to {path:?}");
+    if unknown_accounts > 0 || unknown_slots > 0 {
+        tracing::warn!(
+            "{unknown_accounts} accounts and {unknown_slots} storage slots have no known preimage and are only keyed by their hash, add them to `--preimages` to dump them by address and slot"
+        );
+    }
+    Ok(())
+}
+
+/// Turns a state dump into the `alloc` object of a genesis, reading and writing one account at
+/// a time. Accounts and slots without a preimage can't be placed in a genesis, they are an error
+/// unless `skip_unknown` is set. Contracts are never left out, nor any of their slots, as the
+/// code would run against a different state than the one it wrote.
+pub fn alloc_from_dump(dump: &Path, out: &Path, skip_unknown: bool) -> Result<(), NodeError> {
+    let read_error =
+        |e: String| NodeError::Config(format!("Failed to read state dump {dump:?}: {e}"));
+    let write_error =
+        |e: io::Error| NodeError::Storage(format!("Failed to write alloc to {out:?}: {e}"));
+
+    let reader = BufReader::new(File::open(dump).map_err(|e| read_error(e.to_string()))?);
+    let mut writer = BufWriter::new(File::create(out).map_err(write_error)?);
+    write!(writer, "{{").map_err(write_error)?;
+
+    let (mut accounts, mut skipped_accounts, mut skipped_slots) = (0u64, 0u64, 0u64);
+    for (index, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| read_error(e.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_error = |e: &str| read_error(format!("line {}: {e}", index + 1));
+        let entry: Map<String, Value> =
+            serde_json::from_str(&line).map_err(|e| line_error(&e.to_string()))?;
+
+        let has_code = entry.get("code").is_some();
+        let Some(address) = entry.get("address").and_then(Value::as_str) else {
+            if has_code {
+                return Err(line_error(
+                    "the address of a contract is unknown, it can't be placed in the alloc",
+                ));
+            }
+            if !skip_unknown {
+                return Err(line_error(
+                    "the account address is unknown, use `--skip-unknown` to leave it out",
+                ));
+            }
+            skipped_accounts += 1;
+            continue;
+        };
+        let hashed_slots = entry
+            .get("hashedStorage")
+            .and_then(Value::as_object)
+            .map_or(0, Map::len);
+        if hashed_slots > 0 {
+            if has_code {
+                return Err(line_error(&format!(
+                    "{hashed_slots} storage slots of contract {address} are unknown, it can't be placed in the alloc without them"
+                )));
+            }
+            if !skip_unknown {
+                return Err(line_error(&format!(
+                    "{hashed_slots} storage slots of {address} are unknown, use `--skip-unknown` to leave them out"
+                )));
+            }
+            skipped_slots += hashed_slots as u64;
+        }
+
+        let mut account = Map::new();
+        for field in ["balance", "nonce", "code", "storage"] {
+            if let Some(value) = entry.get(field) {
+                account.insert(field.to_owned(), value.clone());
+            }
+        }
+        let separator = if accounts == 0 { "" } else { "," };
+        write!(
+            writer,
+            "{separator}\n  {}: {}",
+            Value::from(address),
+            Value::Object(account)
+        )
+        .map_err(write_error)?;
+        accounts += 1;
+    }
+
+    writeln!(writer, "\n}}").map_err(write_error)?;
+    writer.flush().map_err(write_error)?;
+
+    println!(
+        "Wrote the alloc of {accounts} accounts to {}",
+        out.display()
+    );
+    if skip_unknown && (skipped_accounts > 0 || skipped_slots > 0) {
+        println!(
+            "Left out {skipped_accounts} accounts and {skipped_slots} storage slots without a known preimage"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use mojave_chain_utils::TestDir;
+
+    use super::*;
+
+    const CONTRACT: &str = "0x000000000000000000000000000000000000ffff";
+    const EOA: &str = "0x00000000000000000000000000000000000000aa";
+    const SLOT_HASH: &str = "0x290decd9548b62a8d60345a988386fc84ba6bc95484008f6362f93160ef3e563";
+
+    /// Writes `lines` as a dump and imports it, returning the alloc.
+    fn import(name: &str, lines: &[Value], skip_unknown: bool) -> Result<Value, String> {
+        let dir = TestDir::new(&format!("dump-{name}"));
+        let (dump, out) = (
+            dir.path().join("state.jsonl"),
+            dir.path().join("alloc.json"),
+        );
+        let contents: Vec<String> = lines.iter().map(Value::to_string).collect();
+        fs::write(&dump, contents.join("\n")).unwrap();
+
+        alloc_from_dump(&dump, &out, skip_unknown)
+            .map(|()| serde_json::from_str(&fs::read_to_string(&out).unwrap()).unwrap())
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    fn account(address: Option<&str>, code: bool, hashed_storage: bool) -> Value {
+        let mut account = json!({
+            "addressHash": SLOT_HASH,
+            "address": address,
+            "balance": "0x1",
+            "nonce": "0x0",
+            "storage": { "0x01": "0x02" },
+        });
+        if code {
+            account["code"] = "0x6000".into();
+        }
+        if hashed_storage {
+            account["hashedStorage"] = json!({ SLOT_HASH: "0x03" });
+        }
+        account
+    }
+
+    #[test]
+    fn known_accounts_are_imported() {
+        let alloc = import(
+            "known",
+            &[
+                account(Some(EOA), false, false),
+                account(Some(CONTRACT), true, false),
+            ],
+            false,
+        )
+        .unwrap();
+        assert_eq!(alloc[EOA]["balance"], "0x1");
+        assert_eq!(alloc[CONTRACT]["code"], "0x6000");
+        assert_eq!(alloc[CONTRACT]["storage"]["0x01"], "0x02");
+        assert!(alloc[EOA].get("addressHash").is_none());
+    }
+
+    #[test]
+    fn unknown_entries_need_skip_unknown() {
+        let dump = [account(None, false, false), account(Some(EOA), false, true)];
+        let error = import("unknown", &dump, false).unwrap_err();
+        assert!(error.contains("--skip-unknown"), "{error}");
+
+        let alloc = import("skipped", &dump, true).unwrap();
+        assert_eq!(alloc.as_object().unwrap().len(), 1);
+        assert_eq!(alloc[EOA]["storage"], json!({ "0x01": "0x02" }));
+    }
+
+    #[test]
+    fn contracts_are_never_left_out() {
+        for (name, contract) in [
+            ("unknown-contract", account(None, true, false)),
+            ("unknown-slots", account(Some(CONTRACT), true, true)),
+        ] {
+            let error = import(name, &[contract], true).unwrap_err();
+            assert!(error.contains("can't be placed in the alloc"), "{error}");
+        }
+    }
+
+    fn genesis() -> Genesis {
+        serde_json::from_str(include_str!("../../test_data/genesis.json")).unwrap()
+    }
+
+    #[test]
+    fn preimages_of_the_genesis_are_known() {
+        let genesis = genesis();
+        let preimages = Preimages::load(&genesis, None).unwrap();
+
+        for (address, account) in &genesis.alloc {
+            assert_eq!(preimages.addresses.get(&hash(address)), Some(address));
+            for slot in account.storage.keys() {
+                let slot = H256(slot.to_big_endian());
+                assert_eq!(preimages.slots.get(&hash(slot)), Some(&slot));
+            }
+        }
+    }
+
+    #[test]
+    fn preimage_table_has_addresses_and_slots() {
+        let dir = TestDir::new("dump-preimages");
+        let table = dir.path().join("preimages.txt");
+        let address = Address::repeat_byte(0xaa);
+        let slot = H256::from_low_u64_be(1);
+        fs::write(
+            &table,
+            format!(
+                "# seen by the indexer\n{address:#x}\n\n  {}\n",
+                hex::encode(slot)
+            ),
+        )
+        .unwrap();
+
+        let preimages = Preimages::load(&genesis(), Some(&table)).unwrap();
+        assert_eq!(preimages.addresses.get(&hash(address)), Some(&address));
+        assert_eq!(preimages.slots.get(&hash(slot)), Some(&slot));
+    }
+
+    #[test]
+    fn preimage_table_refuses_other_values() {
+        let dir = TestDir::new("dump-invalid-preimages");
+        let table = dir.path().join("preimages.txt");
+        for contents in ["0x1234", "not hex"] {
+            fs::write(&table, contents).unwrap();
+            assert!(matches!(
+                Preimages::load(&genesis(), Some(&table)),
+                Err(NodeError::Config(_))
+            ));
+        }
+    }
+}
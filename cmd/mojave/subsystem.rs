@@ -0,0 +1,434 @@
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::AbortHandle,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+/// Delay before the first restart of a failed subsystem, doubled on every further restart.
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+/// A subsystem that ran this long before failing gets its restart budget back.
+const STABLE_RUN: Duration = Duration::from_secs(300);
+
+/// Long running parts of the node. Subsystems are stopped in declaration order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Subsystem {
+    Rpc,
+    Metrics,
+    Follower,
+    Consensus,
+    Sequencer,
+    P2P,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 6] = [
+        Subsystem::Rpc,
+        Subsystem::Metrics,
+        Subsystem::Follower,
+        Subsystem::Consensus,
+        Subsystem::Sequencer,
+        Subsystem::P2P,
+    ];
+
+    /// Whether the subsystem tasks are given the shutdown timeout to finish once cancelled. The
+    /// P2P tasks are spawned by `ethrex_p2p` and the ABCI server blocks its own threads, neither
+    /// observes cancellation so they are aborted right away.
+    fn drains(&self) -> bool {
+        !matches!(self, Subsystem::Consensus | Subsystem::P2P)
+    }
+
+    /// How many times a failed subsystem is restarted before the node is brought down.
+    /// The sequencer is never restarted: a half-applied batch must be looked at by an operator.
+    /// Neither is consensus, CometBFT replays the missing blocks once the node is back, nor the
+    /// follower, which only stops once the node diverged from the sequencer.
+    fn max_restarts(&self) -> u32 {
+        match self {
+            Subsystem::Rpc | Subsystem::Metrics => 5,
+            Subsystem::Follower | Subsystem::Consensus | Subsystem::Sequencer | Subsystem::P2P => 0,
+        }
+    }
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Subsystem::Rpc => write!(f, "RPC"),
+            Subsystem::Metrics => write!(f, "metrics"),
+            Subsystem::Follower => write!(f, "follower"),
+            Subsystem::Consensus => write!(f, "consensus"),
+            Subsystem::Sequencer => write!(f, "sequencer"),
+            Subsystem::P2P => write!(f, "P2P"),
+        }
+    }
+}
+
+/// Why a subsystem task stopped without being cancelled.
+#[derive(Debug, Clone)]
+pub enum ExitReason {
+    Finished,
+    Failed(String),
+    Panicked(String),
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitReason::Finished => write!(f, "returned unexpectedly"),
+            ExitReason::Failed(e) => write!(f, "failed: {e}"),
+            ExitReason::Panicked(msg) => write!(f, "panicked: {msg}"),
+        }
+    }
+}
+
+/// Output of a subsystem task.
+pub trait IntoExitReason {
+    fn into_exit_reason(self) -> ExitReason;
+}
+
+impl IntoExitReason for () {
+    fn into_exit_reason(self) -> ExitReason {
+        ExitReason::Finished
+    }
+}
+
+impl<E: fmt::Display> IntoExitReason for Result<(), E> {
+    fn into_exit_reason(self) -> ExitReason {
+        match self {
+            Ok(()) => ExitReason::Finished,
+            Err(e) => ExitReason::Failed(e.to_string()),
+        }
+    }
+}
+
+/// A subsystem that stopped for good, after exhausting its restarts.
+#[derive(Debug, Clone)]
+pub struct SubsystemExit {
+    pub subsystem: Subsystem,
+    pub reason: ExitReason,
+}
+
+impl fmt::Display for SubsystemExit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.subsystem, self.reason)
+    }
+}
+
+struct SubsystemHandle {
+    cancel_token: CancellationToken,
+    tracker: TaskTracker,
+    /// Running tasks, aborted when they don't finish within the shutdown timeout.
+    tasks: Arc<StdMutex<Vec<AbortHandle>>>,
+}
+
+/// Cancellation token and task tracker of every subsystem, so they can be supervised and
+/// stopped one by one.
+pub struct Subsystems {
+    handles: BTreeMap<Subsystem, SubsystemHandle>,
+    exits_tx: mpsc::UnboundedSender<SubsystemExit>,
+    exits_rx: Mutex<mpsc::UnboundedReceiver<SubsystemExit>>,
+}
+
+impl Default for Subsystems {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Subsystems {
+    pub fn new() -> Self {
+        let handles = Subsystem::ALL
+            .into_iter()
+            .map(|subsystem| {
+                let handle = SubsystemHandle {
+                    cancel_token: CancellationToken::new(),
+                    tracker: TaskTracker::new(),
+                    tasks: Arc::default(),
+                };
+                (subsystem, handle)
+            })
+            .collect();
+        let (exits_tx, exits_rx) = mpsc::unbounded_channel();
+        Self {
+            handles,
+            exits_tx,
+            exits_rx: Mutex::new(exits_rx),
+        }
+    }
+
+    fn handle(&self, subsystem: Subsystem) -> &SubsystemHandle {
+        &self.handles[&subsystem]
+    }
+
+    pub fn cancel_token(&self, subsystem: Subsystem) -> CancellationToken {
+        self.handle(subsystem).cancel_token.clone()
+    }
+
+    pub fn tracker(&self, subsystem: Subsystem) -> TaskTracker {
+        self.handle(subsystem).tracker.clone()
+    }
+
+    /// Spawns the future built by `task` as a supervised task of `subsystem`. It can't be
+    /// restarted, so the node is brought down if it stops before the subsystem is cancelled.
+    ///
+    /// The future gets a token cancelled when the subsystem is stopped, it then has until the
+    /// shutdown timeout to finish its in-flight work before it's aborted.
+    pub fn spawn<F, Fut>(&self, subsystem: Subsystem, task: F)
+    where
+        F: FnOnce(CancellationToken) -> Fut + Send + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: IntoExitReason + Send + 'static,
+    {
+        let mut task = Some(task);
+        self.supervise(subsystem, move |cancel_token| {
+            task.take().map(|task| task(cancel_token))
+        });
+    }
+
+    /// Spawns the future built by `factory` as a supervised task of `subsystem`. A new one is
+    /// built with exponential backoff every time the previous one stops, up to the subsystem
+    /// restart limit. Futures get a cancellation token like with [`Subsystems::spawn`].
+    pub fn spawn_with_restart<F, Fut>(&self, subsystem: Subsystem, mut factory: F)
+    where
+        F: FnMut(CancellationToken) -> Fut + Send + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: IntoExitReason + Send + 'static,
+    {
+        self.supervise(subsystem, move |cancel_token| Some(factory(cancel_token)));
+    }
+
+    fn supervise<F, Fut>(&self, subsystem: Subsystem, mut factory: F)
+    where
+        F: FnMut(CancellationToken) -> Option<Fut> + Send + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: IntoExitReason + Send + 'static,
+    {
+        let handle = self.handle(subsystem);
+        let cancel_token = handle.cancel_token.clone();
+        let tracker = handle.tracker.clone();
+        let tasks = handle.tasks.clone();
+        let exits_tx = self.exits_tx.clone();
+
+        handle.tracker.spawn(async move {
+            let Some(mut future) = factory(cancel_token.child_token()) else {
+                return;
+            };
+            let mut restarts = 0;
+            loop {
+                let started = Instant::now();
+                let mut task = {
+                    // `stop` cancels before taking the tasks, so under the lock a task is either
+                    // not spawned or registered in time to be aborted.
+                    let mut tasks = tasks.lock().unwrap_or_else(|e| e.into_inner());
+                    if cancel_token.is_cancelled() {
+                        return;
+                    }
+                    let task = tracker.spawn(future);
+                    tasks.retain(|task| !task.is_finished());
+                    tasks.push(task.abort_handle());
+                    task
+                };
+
+                let result = tokio::select! {
+                    result = &mut task => Some(result),
+                    _ = cancel_token.cancelled() => None,
+                };
+                let Some(result) = result else {
+                    // The task is draining, `stop` aborts it once the timeout has passed.
+                    let _ = task.await;
+                    return;
+                };
+                let reason = match result {
+                    Ok(output) => output.into_exit_reason(),
+                    Err(e) if e.is_panic() => ExitReason::Panicked(panic_message(e.into_panic())),
+                    Err(e) => ExitReason::Failed(e.to_string()),
+                };
+
+                if started.elapsed() >= STABLE_RUN {
+                    restarts = 0;
+                }
+                let next = if restarts < subsystem.max_restarts() {
+                    factory(cancel_token.child_token())
+                } else {
+                    None
+                };
+                let Some(next) = next else {
+                    tracing::error!("{subsystem} {reason}");
+                    let _ = exits_tx.send(SubsystemExit { subsystem, reason });
+                    return;
+                };
+
+                let backoff = RESTART_BACKOFF
+                    .saturating_mul(2u32.saturating_pow(restarts))
+                    .min(MAX_RESTART_BACKOFF);
+                restarts += 1;
+                tracing::warn!(
+                    "{subsystem} {reason}, restarting in {backoff:?} ({restarts}/{})",
+                    subsystem.max_restarts()
+                );
+
+                tokio::select! {
+                    _ = cancel_token.cancelled() => return,
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+                future = next;
+            }
+        });
+    }
+
+    /// Waits until a subsystem stops for good.
+    pub async fn exited(&self) -> SubsystemExit {
+        let mut exits_rx = self.exits_rx.lock().await;
+        // The sender lives in `self`, so the channel is never closed while we wait.
+        match exits_rx.recv().await {
+            Some(exit) => exit,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Cancels the subsystem and waits up to `timeout` for its tasks to finish, aborting the
+    /// ones still running after that.
+    pub async fn stop(&self, subsystem: Subsystem, timeout: Duration) {
+        let handle = self.handle(subsystem);
+        tracing::info!("Stopping {subsystem}...");
+        handle.cancel_token.cancel();
+        handle.tracker.close();
+
+        if subsystem.drains() {
+            if tokio::time::timeout(timeout, handle.tracker.wait())
+                .await
+                .is_ok()
+            {
+                tracing::info!("{subsystem} stopped");
+                return;
+            }
+            tracing::warn!("{subsystem} did not stop within {timeout:?}, aborting its tasks");
+        }
+
+        let tasks = std::mem::take(&mut *handle.tasks.lock().unwrap_or_else(|e| e.into_inner()));
+        for task in tasks {
+            task.abort();
+        }
+        // Aborted tasks stop at their next await point, a task blocking its thread is left behind.
+        match tokio::time::timeout(timeout, handle.tracker.wait()).await {
+            Ok(()) => tracing::info!("{subsystem} stopped"),
+            Err(_) => tracing::warn!(
+                "{subsystem} tasks did not stop once aborted, {} tasks abandoned",
+                handle.tracker.len()
+            ),
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic payload".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    /// Sets its flag when dropped, so a test sees when a task future is gone.
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn draining_task_finishes_its_work() {
+        let subsystems = Subsystems::new();
+        let drained = Arc::new(AtomicBool::new(false));
+        let task_drained = drained.clone();
+        let (started_tx, started) = oneshot::channel();
+        subsystems.spawn(Subsystem::Rpc, move |cancel_token| async move {
+            let _ = started_tx.send(());
+            cancel_token.cancelled().await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            task_drained.store(true, Ordering::SeqCst);
+        });
+        started.await.unwrap();
+
+        subsystems
+            .stop(Subsystem::Rpc, Duration::from_secs(5))
+            .await;
+        assert!(drained.load(Ordering::SeqCst));
+        assert!(subsystems.tracker(Subsystem::Rpc).is_empty());
+    }
+
+    #[tokio::test]
+    async fn task_ignoring_cancellation_is_aborted_after_the_timeout() {
+        let subsystems = Subsystems::new();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = DropFlag(dropped.clone());
+        let (started_tx, started) = oneshot::channel();
+        subsystems.spawn(Subsystem::Rpc, move |_| async move {
+            let _flag = flag;
+            let _ = started_tx.send(());
+            std::future::pending::<()>().await;
+        });
+        started.await.unwrap();
+
+        let started = Instant::now();
+        subsystems
+            .stop(Subsystem::Rpc, Duration::from_millis(100))
+            .await;
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(dropped.load(Ordering::SeqCst));
+        assert!(subsystems.tracker(Subsystem::Rpc).is_empty());
+    }
+
+    #[tokio::test]
+    async fn non_draining_subsystem_is_aborted_right_away() {
+        let subsystems = Subsystems::new();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = DropFlag(dropped.clone());
+        let (started_tx, started) = oneshot::channel();
+        subsystems.spawn(Subsystem::P2P, move |_| async move {
+            let _flag = flag;
+            let _ = started_tx.send(());
+            std::future::pending::<()>().await;
+        });
+        started.await.unwrap();
+
+        let started = Instant::now();
+        subsystems
+            .stop(Subsystem::P2P, Duration::from_secs(5))
+            .await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn failed_task_is_reported_with_its_error() {
+        let subsystems = Subsystems::new();
+        subsystems.spawn(Subsystem::Sequencer, |_| async {
+            Err::<(), _>(std::io::Error::other("batch rejected"))
+        });
+
+        let exit = subsystems.exited().await;
+        assert_eq!(exit.subsystem, Subsystem::Sequencer);
+        assert!(matches!(exit.reason, ExitReason::Failed(ref e) if e == "batch rejected"));
+    }
+}
@@ -0,0 +1,389 @@
+use std::{
+    collections::{HashMap, HashSet},
+    future::IntoFuture,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::IntoResponse,
+    routing::any,
+    Router,
+};
+use ethrex_common::H256;
+use ethrex_rpc::{map_http_requests, rpc_response, RpcApiContext, RpcRequest, RpcRequestId};
+use serde_json::{json, Map, Value};
+use tokio::{net::TcpListener, sync::broadcast};
+use tokio_util::sync::CancellationToken;
+
+/// How often the store is checked for new blocks and the mempool for new transactions, neither
+/// notifies on changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Notifications buffered per subscriber before it starts missing them.
+const CHANNEL_CAPACITY: usize = 1024;
+
+const INVALID_REQUEST: i64 = -32600;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+/// JSON-RPC over WebSocket.
+///
+/// `eth_subscribe` and `eth_unsubscribe` are served here, every other method is dispatched in
+/// process to the ethrex handlers with a context sharing the store and blockchain of the HTTP
+/// server.
+#[derive(Clone)]
+struct WsContext {
+    rpc: RpcApiContext,
+    heads: broadcast::Sender<Arc<Value>>,
+    pending_txs: broadcast::Sender<Arc<Value>>,
+    next_subscription_id: Arc<AtomicU64>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Subscription {
+    NewHeads,
+    /// `eth_getLogs` filter object, its block range is set to each new head.
+    Logs(Map<String, Value>),
+    NewPendingTransactions,
+}
+
+impl Subscription {
+    /// Parses the `eth_subscribe` params, `[kind]` or `["logs", filter]`.
+    fn from_params(params: &Value) -> Result<Self, String> {
+        match params.get(0).and_then(Value::as_str) {
+            Some("newHeads") => Ok(Subscription::NewHeads),
+            Some("logs") => match params.get(1) {
+                None | Some(Value::Null) => Ok(Subscription::Logs(Map::new())),
+                Some(Value::Object(filter)) => Ok(Subscription::Logs(filter.clone())),
+                Some(other) => Err(format!("Logs filter must be an object, got {other}")),
+            },
+            Some("newPendingTransactions") => Ok(Subscription::NewPendingTransactions),
+            other => Err(format!("Unsupported subscription: {other:?}")),
+        }
+    }
+}
+
+/// Serves the WS API until `cancel_token` is cancelled. Open connections are dropped with it.
+pub async fn start_ws_api(
+    addr: SocketAddr,
+    rpc: RpcApiContext,
+    cancel_token: CancellationToken,
+) -> Result<(), std::io::Error> {
+    let context = WsContext {
+        rpc,
+        heads: broadcast::channel(CHANNEL_CAPACITY).0,
+        pending_txs: broadcast::channel(CHANNEL_CAPACITY).0,
+        next_subscription_id: Arc::new(AtomicU64::new(1)),
+    };
+
+    let router = Router::new()
+        .route("/", any(handle_upgrade))
+        .with_state(context.clone());
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("Starting WS server at {addr}");
+
+    tokio::select! {
+        result = axum::serve(listener, router).into_future() => result,
+        _ = context.clone().poll_heads() => Ok(()),
+        _ = context.poll_pending_txs() => Ok(()),
+        _ = cancel_token.cancelled() => Ok(()),
+    }
+}
+
+async fn handle_upgrade(
+    ws: WebSocketUpgrade,
+    State(context): State<WsContext>,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| context.handle_socket(socket))
+}
+
+fn error_response(id: Value, code: i64, message: impl Into<String>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message.into() },
+    })
+}
+
+fn notification(subscription: &str, result: &Value) -> Message {
+    let notification = json!({
+        "jsonrpc": "2.0",
+        "method": "eth_subscription",
+        "params": { "subscription": subscription, "result": result },
+    });
+    Message::Text(notification.to_string().into())
+}
+
+impl WsContext {
+    async fn handle_socket(self, mut socket: WebSocket) {
+        let mut subscriptions: HashMap<String, Subscription> = HashMap::new();
+        let mut heads = self.heads.subscribe();
+        let mut pending_txs = self.pending_txs.subscribe();
+
+        loop {
+            let outgoing = tokio::select! {
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        vec![Message::Text(self.handle_message(&text, &mut subscriptions).await.to_string().into())]
+                    }
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                    Some(Ok(_)) => continue,
+                },
+                head = heads.recv() => match head {
+                    Ok(head) => self.head_notifications(&head, &subscriptions).await,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("WS subscriber lagged behind, {skipped} heads skipped");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                tx_hash = pending_txs.recv() => match tx_hash {
+                    Ok(tx_hash) => subscriptions
+                        .iter()
+                        .filter(|(_, subscription)| matches!(subscription, Subscription::NewPendingTransactions))
+                        .map(|(id, _)| notification(id, &tx_hash))
+                        .collect(),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("WS subscriber lagged behind, {skipped} transactions skipped");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            };
+
+            for message in outgoing {
+                if socket.send(message).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn handle_message(
+        &self,
+        text: &str,
+        subscriptions: &mut HashMap<String, Subscription>,
+    ) -> Value {
+        match serde_json::from_str::<Value>(text) {
+            Ok(Value::Array(calls)) => {
+                let mut responses = Vec::with_capacity(calls.len());
+                for call in calls {
+                    responses.push(self.handle_call(call, subscriptions).await);
+                }
+                Value::Array(responses)
+            }
+            Ok(call) => self.handle_call(call, subscriptions).await,
+            Err(e) => error_response(Value::Null, INVALID_REQUEST, e.to_string()),
+        }
+    }
+
+    async fn handle_call(
+        &self,
+        call: Value,
+        subscriptions: &mut HashMap<String, Subscription>,
+    ) -> Value {
+        let id = call.get("id").cloned().unwrap_or(Value::Null);
+        let params = call.get("params").cloned().unwrap_or(Value::Null);
+
+        match call.get("method").and_then(Value::as_str) {
+            Some("eth_subscribe") => {
+                let subscription = match Subscription::from_params(&params) {
+                    Ok(subscription) => subscription,
+                    Err(message) => return error_response(id, INVALID_PARAMS, message),
+                };
+                let subscription_id = format!(
+                    "{:#x}",
+                    self.next_subscription_id.fetch_add(1, Ordering::Relaxed)
+                );
+                subscriptions.insert(subscription_id.clone(), subscription);
+                json!({ "jsonrpc": "2.0", "id": id, "result": subscription_id })
+            }
+            Some("eth_unsubscribe") => {
+                let removed = params
+                    .get(0)
+                    .and_then(Value::as_str)
+                    .is_some_and(|subscription_id| subscriptions.remove(subscription_id).is_some());
+                json!({ "jsonrpc": "2.0", "id": id, "result": removed })
+            }
+            _ => {
+                let request: RpcRequest = match serde_json::from_value(call) {
+                    Ok(request) => request,
+                    Err(e) => return error_response(id, INVALID_REQUEST, e.to_string()),
+                };
+                let result = map_http_requests(&request, self.rpc.clone()).await;
+                rpc_response(request.id, result)
+                    .unwrap_or_else(|e| error_response(id, INTERNAL_ERROR, format!("{e:?}")))
+            }
+        }
+    }
+
+    /// Calls `method` on the ethrex handlers and returns its result.
+    async fn call(&self, method: &str, params: Vec<Value>) -> Option<Value> {
+        let request = RpcRequest {
+            id: RpcRequestId::Number(1),
+            jsonrpc: "2.0".to_owned(),
+            method: method.to_owned(),
+            params: Some(params),
+        };
+        match map_http_requests(&request, self.rpc.clone()).await {
+            Ok(result) if !result.is_null() => Some(result),
+            Ok(_) => {
+                tracing::debug!("{method} returned no result");
+                None
+            }
+            Err(e) => {
+                tracing::warn!("Failed to call {method} for WS subscriptions: {e:?}");
+                None
+            }
+        }
+    }
+
+    async fn head_notifications(
+        &self,
+        head: &Value,
+        subscriptions: &HashMap<String, Subscription>,
+    ) -> Vec<Message> {
+        let mut messages = Vec::new();
+        for (id, subscription) in subscriptions {
+            match subscription {
+                Subscription::NewHeads => messages.push(notification(id, head)),
+                Subscription::Logs(filter) => {
+                    let mut filter = filter.clone();
+                    filter.insert("fromBlock".to_owned(), head["number"].clone());
+                    filter.insert("toBlock".to_owned(), head["number"].clone());
+                    if let Some(Value::Array(logs)) =
+                        self.call("eth_getLogs", vec![Value::Object(filter)]).await
+                    {
+                        messages.extend(logs.iter().map(|log| notification(id, log)));
+                    }
+                }
+                Subscription::NewPendingTransactions => {}
+            }
+        }
+        messages
+    }
+
+    /// Broadcasts the header of every block added to the store.
+    async fn poll_heads(self) {
+        let mut last_number = self
+            .rpc
+            .storage
+            .get_latest_block_number()
+            .await
+            .unwrap_or_default();
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let latest_number = match self.rpc.storage.get_latest_block_number().await {
+                Ok(number) => number,
+                Err(e) => {
+                    tracing::warn!("Failed to read latest block for WS subscriptions: {e}");
+                    continue;
+                }
+            };
+            // Nobody is subscribed, skip the lookups and catch up silently.
+            if self.heads.receiver_count() == 0 {
+                last_number = latest_number;
+                continue;
+            }
+            while last_number < latest_number {
+                let number = last_number + 1;
+                let Some(Value::Object(mut header)) = self
+                    .call(
+                        "eth_getBlockByNumber",
+                        vec![json!(format!("{number:#x}")), json!(false)],
+                    )
+                    .await
+                else {
+                    break;
+                };
+                for body_field in ["transactions", "uncles", "withdrawals"] {
+                    header.remove(body_field);
+                }
+                let _ = self.heads.send(Arc::new(Value::Object(header)));
+                last_number = number;
+            }
+        }
+    }
+
+    /// Broadcasts the hash of every transaction that enters the mempool.
+    async fn poll_pending_txs(self) {
+        // `None` while nobody is connected. The pool found when the first subscriber connects
+        // only seeds it, those transactions are not new to the subscriber.
+        let mut known: Option<HashSet<H256>> = None;
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            if self.pending_txs.receiver_count() == 0 {
+                known = None;
+                continue;
+            }
+            let pending: HashSet<H256> = match self.rpc.blockchain.mempool.content() {
+                Ok(txs) => txs.iter().map(|tx| tx.compute_hash()).collect(),
+                Err(e) => {
+                    tracing::warn!("Failed to read the mempool for WS subscriptions: {e}");
+                    continue;
+                }
+            };
+            if let Some(known) = &known {
+                for tx_hash in pending.difference(known) {
+                    let _ = self.pending_txs.send(Arc::new(json!(tx_hash)));
+                }
+            }
+            known = Some(pending);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_filter_is_optional() {
+        assert_eq!(
+            Subscription::from_params(&json!(["logs"])),
+            Ok(Subscription::Logs(Map::new()))
+        );
+        assert_eq!(
+            Subscription::from_params(&json!(["logs", null])),
+            Ok(Subscription::Logs(Map::new()))
+        );
+    }
+
+    #[test]
+    fn logs_filter_object_is_kept() {
+        let filter = json!({ "address": "0x0000000000000000000000000000000000000001" });
+        assert_eq!(
+            Subscription::from_params(&json!(["logs", filter])),
+            Ok(Subscription::Logs(filter.as_object().unwrap().clone()))
+        );
+    }
+
+    #[test]
+    fn non_object_logs_filters_are_refused() {
+        for filter in [json!("0x1"), json!(1), json!(true), json!([])] {
+            let error = Subscription::from_params(&json!(["logs", filter])).unwrap_err();
+            assert!(error.contains("must be an object"), "{error}");
+        }
+    }
+
+    #[test]
+    fn unsupported_subscriptions_are_refused() {
+        assert_eq!(
+            Subscription::from_params(&json!(["newHeads"])),
+            Ok(Subscription::NewHeads)
+        );
+        assert!(Subscription::from_params(&json!(["syncing"])).is_err());
+        assert!(Subscription::from_params(&json!([])).is_err());
+        assert!(Subscription::from_params(&Value::Null).is_err());
+    }
+}
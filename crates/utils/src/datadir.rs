@@ -0,0 +1,283 @@
+use std::{
+    env,
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+/// `--datadir` value selecting the in-memory storage engines.
+pub const MEMORY_DATADIR: &str = "memory";
+
+/// Directory of the default datadirs, one per network, inside the XDG data directory.
+const APP_DIR: &str = "mojave";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DatadirError {
+    #[error("HOME is not set, the datadir can't be resolved. Use an absolute `--datadir`")]
+    NoHome,
+}
+
+/// Where a node keeps its databases and files.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Datadir {
+    Disk(PathBuf),
+    /// Databases live in memory and are lost on exit, there are no files.
+    Memory,
+}
+
+impl Datadir {
+    /// Resolves a `--datadir` value. `memory` selects the in-memory engines, a leading `~` is
+    /// the home directory and relative paths are made absolute against the working directory.
+    /// Without a value the datadir is `$XDG_DATA_HOME/mojave/<network>`, with `XDG_DATA_HOME`
+    /// defaulting to `~/.local/share`. Fails when the home directory is needed but `HOME` isn't
+    /// set.
+    pub fn resolve(datadir: Option<&str>, network: &str) -> Result<Self, DatadirError> {
+        Self::resolve_with(datadir, network, &|name| env::var_os(name))
+    }
+
+    /// [`Datadir::resolve`] reading the environment variables from `var`.
+    fn resolve_with(
+        datadir: Option<&str>,
+        network: &str,
+        var: &dyn Fn(&str) -> Option<OsString>,
+    ) -> Result<Self, DatadirError> {
+        let path = match datadir {
+            Some(MEMORY_DATADIR) => return Ok(Datadir::Memory),
+            Some(path) => expand_home(path, var)?,
+            None => data_home(var)?.join(APP_DIR).join(network),
+        };
+        Ok(Datadir::Disk(std::path::absolute(&path).unwrap_or(path)))
+    }
+
+    /// Directory of the datadir, `None` in memory.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Datadir::Disk(path) => Some(path),
+            Datadir::Memory => None,
+        }
+    }
+
+    pub fn is_memory(&self) -> bool {
+        matches!(self, Datadir::Memory)
+    }
+
+    /// Whether the datadir is on disk and exists.
+    pub fn exists(&self) -> bool {
+        self.path().is_some_and(Path::exists)
+    }
+
+    fn join(&self, name: &str) -> Option<PathBuf> {
+        self.path().map(|path| path.join(name))
+    }
+
+    /// Datadir of the rollup store, in memory when this one is.
+    pub fn rollup_store(&self) -> Datadir {
+        match self {
+            Datadir::Disk(path) => Datadir::Disk(path.join("rollup_store")),
+            Datadir::Memory => Datadir::Memory,
+        }
+    }
+
+    /// Files and directories of the databases, what `removedb` deletes: the libmdbx files of the
+    /// store, the rollup store and the chain record describing them. Keys, genesis and config
+    /// files are not part of it.
+    pub fn databases(&self) -> Vec<PathBuf> {
+        ["mdbx.dat", "mdbx.lck", "rollup_store", "chain.json"]
+            .into_iter()
+            .filter_map(|name| self.join(name))
+            .collect()
+    }
+
+    /// Lock file held by the process using the datadir.
+    pub fn lock_file(&self) -> Option<PathBuf> {
+        self.join("mojave.lock")
+    }
+
+    /// Manifest with the layout version of the datadir.
+    pub fn meta_file(&self) -> Option<PathBuf> {
+        self.join("mojave.meta")
+    }
+
+    /// Secret key of the P2P identity.
+    pub fn node_key(&self) -> Option<PathBuf> {
+        self.join("node.key")
+    }
+
+    /// Node record and known peers, stored on shutdown.
+    pub fn node_config(&self) -> Option<PathBuf> {
+        self.join("node_config.json")
+    }
+
+    /// Directory of the encrypted key files.
+    pub fn keystore(&self) -> Option<PathBuf> {
+        self.join("keystore")
+    }
+
+    /// Chain id and genesis hash of the chain of the database.
+    pub fn chain_record(&self) -> Option<PathBuf> {
+        self.join("chain.json")
+    }
+
+    /// Last block proposed by the validator. Not CometBFT's `priv_validator_state.json`, which
+    /// CometBFT keeps for its own signatures.
+    pub fn proposal_record(&self) -> Option<PathBuf> {
+        self.join("mojave_proposal.json")
+    }
+
+    pub fn genesis(&self) -> Option<PathBuf> {
+        self.join("genesis.json")
+    }
+
+    pub fn jwt_secret(&self) -> Option<PathBuf> {
+        self.join("jwt.hex")
+    }
+
+    pub fn config(&self) -> Option<PathBuf> {
+        self.join("mojave.toml")
+    }
+}
+
+/// The directory path, or `memory` for the in-memory engines as the ethrex initializers take it.
+impl fmt::Display for Datadir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Datadir::Disk(path) => write!(f, "{}", path.display()),
+            Datadir::Memory => f.write_str(MEMORY_DATADIR),
+        }
+    }
+}
+
+fn home_dir(var: &dyn Fn(&str) -> Option<OsString>) -> Result<PathBuf, DatadirError> {
+    var("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+        .ok_or(DatadirError::NoHome)
+}
+
+fn expand_home(
+    path: &str,
+    var: &dyn Fn(&str) -> Option<OsString>,
+) -> Result<PathBuf, DatadirError> {
+    match path.strip_prefix('~') {
+        Some("") => home_dir(var),
+        Some(rest) if rest.starts_with('/') => Ok(home_dir(var)?.join(&rest[1..])),
+        _ => Ok(PathBuf::from(path)),
+    }
+}
+
+/// `$XDG_DATA_HOME`, ignored when it isn't absolute as the XDG spec requires.
+fn data_home(var: &dyn Fn(&str) -> Option<OsString>) -> Result<PathBuf, DatadirError> {
+    match var("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+    {
+        Some(path) => Ok(path),
+        None => Ok(home_dir(var)?.join(".local/share")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn resolve(datadir: Option<&str>, vars: &[(&str, &str)]) -> Result<Datadir, DatadirError> {
+        let vars: HashMap<&str, OsString> = vars
+            .iter()
+            .map(|(name, value)| (*name, OsString::from(value)))
+            .collect();
+        Datadir::resolve_with(datadir, "testnet", &|name| vars.get(name).cloned())
+    }
+
+    fn disk(path: &str) -> Datadir {
+        Datadir::Disk(PathBuf::from(path))
+    }
+
+    #[test]
+    fn memory_has_no_files() {
+        let datadir = resolve(Some("memory"), &[]).unwrap();
+        assert_eq!(datadir, Datadir::Memory);
+        assert!(datadir.is_memory());
+        assert_eq!(datadir.to_string(), "memory");
+        assert_eq!(datadir.lock_file(), None);
+        assert_eq!(datadir.keystore(), None);
+        assert_eq!(datadir.rollup_store(), Datadir::Memory);
+        assert!(datadir.databases().is_empty());
+        assert!(!datadir.exists());
+    }
+
+    #[test]
+    fn databases_exclude_keys_and_config() {
+        let datadir = disk("/data");
+        let databases = datadir.databases();
+        assert!(databases.contains(&PathBuf::from("/data/rollup_store")));
+        assert!(databases.contains(&datadir.chain_record().unwrap()));
+        for kept in [
+            datadir.keystore(),
+            datadir.node_key(),
+            datadir.node_config(),
+            datadir.genesis(),
+            datadir.jwt_secret(),
+            datadir.config(),
+            datadir.lock_file(),
+            datadir.meta_file(),
+            datadir.proposal_record(),
+        ] {
+            assert!(!databases.contains(&kept.unwrap()));
+        }
+    }
+
+    #[test]
+    fn tilde_is_the_home_directory() {
+        let home = [("HOME", "/home/node")];
+        assert_eq!(resolve(Some("~"), &home).unwrap(), disk("/home/node"));
+        assert_eq!(
+            resolve(Some("~/chain"), &home).unwrap(),
+            disk("/home/node/chain")
+        );
+        // Only a leading `~/` is expanded.
+        assert_eq!(resolve(Some("/data/~x"), &home).unwrap(), disk("/data/~x"));
+    }
+
+    #[test]
+    fn relative_paths_are_made_absolute() {
+        let datadir = resolve(Some("chain"), &[]).unwrap();
+        assert_eq!(
+            datadir.path().unwrap(),
+            std::env::current_dir().unwrap().join("chain")
+        );
+    }
+
+    #[test]
+    fn default_is_in_the_xdg_data_home() {
+        assert_eq!(
+            resolve(None, &[("HOME", "/home/node"), ("XDG_DATA_HOME", "/xdg")]).unwrap(),
+            disk("/xdg/mojave/testnet")
+        );
+        assert_eq!(
+            resolve(None, &[("HOME", "/home/node")]).unwrap(),
+            disk("/home/node/.local/share/mojave/testnet")
+        );
+        // A relative XDG_DATA_HOME is ignored, as the XDG spec requires.
+        assert_eq!(
+            resolve(None, &[("HOME", "/home/node"), ("XDG_DATA_HOME", "xdg")]).unwrap(),
+            disk("/home/node/.local/share/mojave/testnet")
+        );
+    }
+
+    #[test]
+    fn home_is_required_when_used() {
+        for datadir in [None, Some("~"), Some("~/chain")] {
+            assert!(matches!(
+                resolve(datadir, &[("HOME", "")]),
+                Err(DatadirError::NoHome)
+            ));
+        }
+        assert_eq!(
+            resolve(None, &[("XDG_DATA_HOME", "/xdg")]).unwrap(),
+            disk("/xdg/mojave/testnet")
+        );
+        assert_eq!(resolve(Some("/data"), &[]).unwrap(), disk("/data"));
+    }
+}
@@ -1,6 +1,50 @@
-use std::path::Path;
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
 
-pub fn resolve_datadir(datadir: &str) -> String {
-    const BASE: &str = ".";
-    Path::new(BASE).join(datadir).to_string_lossy().into_owned()
+/// Replaces `path` with `contents` so a crash leaves either the old or the new file, never a
+/// partial one.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    // The rename is only durable once the directory entry is flushed.
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Empty directory for a test, removed with its contents when dropped.
+#[cfg(any(test, feature = "test-utils"))]
+pub struct TestDir(PathBuf);
+
+#[cfg(any(test, feature = "test-utils"))]
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("mojave-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
 }
@@ -1,3 +1,12 @@
+mod datadir;
 mod fs;
+mod lock;
+mod migration;
+mod proposal_record;
 
-pub use fs::resolve_datadir;
+pub use datadir::{Datadir, DatadirError, MEMORY_DATADIR};
+#[cfg(any(test, feature = "test-utils"))]
+pub use fs::TestDir;
+pub use lock::{DatadirLock, DatadirLockError, LockOwner};
+pub use migration::{DATADIR_VERSION, DatadirMeta, Migration, MigrationError, run_migrations};
+pub use proposal_record::{ProposalRecord, ProposalRecordError};
@@ -0,0 +1,179 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Process recorded in a lock file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockOwner {
+    pub pid: u32,
+    /// Unix time in seconds.
+    pub started_at: u64,
+}
+
+impl fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "process {} started at unix time {}",
+            self.pid, self.started_at
+        )
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DatadirLockError {
+    #[error("Lock file {path:?} is held by {}", holder(.owner))]
+    Held {
+        path: PathBuf,
+        owner: Option<LockOwner>,
+    },
+    #[error("Failed to access lock file: {0}")]
+    Io(#[from] io::Error),
+}
+
+fn holder(owner: &Option<LockOwner>) -> String {
+    owner.map_or_else(|| "another process".to_owned(), |owner| owner.to_string())
+}
+
+/// Exclusive advisory lock on a datadir, held for as long as this value lives.
+///
+/// The lock is taken on the file itself, so the OS releases it when the holder dies. A file
+/// still naming a process after that is stale: the next process takes it over and reports the
+/// previous owner through [`DatadirLock::stale_owner`].
+#[derive(Debug)]
+pub struct DatadirLock {
+    file: File,
+    path: PathBuf,
+    stale_owner: Option<LockOwner>,
+}
+
+impl DatadirLock {
+    /// Locks the file at `path`, creating it and its directory, and records this process in it.
+    pub fn acquire(path: impl Into<PathBuf>) -> Result<Self, DatadirLockError> {
+        let path = path.into();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let owner = read_owner(&mut file);
+                return Err(DatadirLockError::Held { path, owner });
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        let stale_owner = read_owner(&mut file);
+        let owner = LockOwner {
+            pid: process::id(),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_secs()),
+        };
+        let contents = serde_json::to_vec(&owner).map_err(io::Error::other)?;
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+
+        Ok(Self {
+            file,
+            path,
+            stale_owner,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Owner of a lock file left behind by a process that didn't release it, usually a crash.
+    pub fn stale_owner(&self) -> Option<LockOwner> {
+        self.stale_owner
+    }
+}
+
+impl Drop for DatadirLock {
+    /// Empties the file so a clean exit leaves no stale owner. The file isn't removed, another
+    /// process may already be waiting on it and would lock an unlinked file.
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Owner recorded in the lock file, `None` when it's empty or unreadable.
+fn read_owner(file: &mut File) -> Option<LockOwner> {
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::TestDir;
+
+    #[test]
+    fn second_lock_is_refused_with_the_owner() {
+        let dir = TestDir::new("lock-contention");
+        let path = dir.path().join("datadir/mojave.lock");
+        let lock = DatadirLock::acquire(&path).unwrap();
+        assert_eq!(lock.path(), path);
+        assert_eq!(lock.stale_owner(), None);
+
+        match DatadirLock::acquire(&path) {
+            Err(DatadirLockError::Held { path: held, owner }) => {
+                assert_eq!(held, path);
+                assert_eq!(owner.map(|owner| owner.pid), Some(process::id()));
+            }
+            other => panic!("expected the lock to be held, got {other:?}"),
+        }
+
+        drop(lock);
+        let lock = DatadirLock::acquire(&path).unwrap();
+        assert_eq!(lock.stale_owner(), None);
+    }
+
+    #[test]
+    fn unreleased_lock_file_reports_its_owner() {
+        let dir = TestDir::new("lock-stale");
+        let path = dir.path().join("mojave.lock");
+        let crashed = LockOwner {
+            pid: 4_000_000,
+            started_at: 1_700_000_000,
+        };
+        fs::write(&path, serde_json::to_vec(&crashed).unwrap()).unwrap();
+
+        let lock = DatadirLock::acquire(&path).unwrap();
+        assert_eq!(lock.stale_owner(), Some(crashed));
+        let recorded: LockOwner = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(recorded.pid, process::id());
+
+        drop(lock);
+        assert!(fs::read(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unreadable_owner_is_ignored() {
+        let dir = TestDir::new("lock-garbage");
+        let path = dir.path().join("mojave.lock");
+        fs::write(&path, "not json").unwrap();
+
+        let lock = DatadirLock::acquire(&path).unwrap();
+        assert_eq!(lock.stale_owner(), None);
+    }
+}
@@ -0,0 +1,317 @@
+use std::{fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{datadir::Datadir, fs::write_atomic};
+
+/// Layout version of the datadirs written by this build.
+pub const DATADIR_VERSION: u32 = 1;
+
+/// Version of datadirs without a manifest, written before it existed.
+const UNVERSIONED: u32 = 1;
+
+/// Migrations in version order, each bringing a datadir from the previous version to its own.
+const MIGRATIONS: &[Migration] = &[];
+
+/// A step of the datadir layout.
+#[derive(Debug)]
+pub struct Migration {
+    /// Version of the datadir once the migration is done.
+    pub version: u32,
+    pub description: &'static str,
+    /// Runs on the datadir directory. The manifest is only updated once this returns, so an
+    /// interrupted migration runs again from the start: it has to be idempotent.
+    pub run: fn(&Path) -> Result<(), String>,
+}
+
+/// Contents of `mojave.meta`, the manifest of a datadir.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatadirMeta {
+    pub version: u32,
+    /// Client that last opened the datadir.
+    pub written_by: String,
+}
+
+impl DatadirMeta {
+    /// Reads the manifest at `path`, `None` if there is none.
+    pub fn load(path: &Path) -> Result<Option<Self>, MigrationError> {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map(Some)
+                .map_err(|e| MigrationError::Parse(e.to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn store(&self, path: &Path) -> Result<(), MigrationError> {
+        let contents =
+            serde_json::to_vec_pretty(self).map_err(|e| MigrationError::Parse(e.to_string()))?;
+        Ok(write_atomic(path, &contents)?)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    #[error(
+        "Datadir version {version} written by {written_by} is newer than version {supported} of this build"
+    )]
+    TooNew {
+        version: u32,
+        written_by: String,
+        supported: u32,
+    },
+    #[error("Migration to version {version} ({description}) failed: {reason}")]
+    Failed {
+        version: u32,
+        description: &'static str,
+        reason: String,
+    },
+    #[error("Failed to access datadir manifest: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid datadir manifest: {0}")]
+    Parse(String),
+}
+
+/// Brings `datadir` to `DATADIR_VERSION`, running the migrations it's missing in order and
+/// recording each version in the manifest as soon as it's reached. Datadirs of a newer version
+/// are refused untouched. `on_migration` is called before each migration runs.
+///
+/// Returns the version the datadir had. In-memory datadirs are always current.
+pub fn run_migrations(
+    datadir: &Datadir,
+    written_by: &str,
+    on_migration: impl FnMut(&Migration),
+) -> Result<u32, MigrationError> {
+    migrate(
+        datadir,
+        MIGRATIONS,
+        DATADIR_VERSION,
+        written_by,
+        on_migration,
+    )
+}
+
+/// [`run_migrations`] with the migrations bringing a datadir to version `target`.
+fn migrate(
+    datadir: &Datadir,
+    migrations: &[Migration],
+    target: u32,
+    written_by: &str,
+    mut on_migration: impl FnMut(&Migration),
+) -> Result<u32, MigrationError> {
+    let (Some(dir), Some(meta_path)) = (datadir.path(), datadir.meta_file()) else {
+        return Ok(target);
+    };
+    let meta = DatadirMeta::load(&meta_path)?;
+    let from = meta.as_ref().map_or(UNVERSIONED, |meta| meta.version);
+    if from > target {
+        return Err(MigrationError::TooNew {
+            version: from,
+            written_by: meta.map(|meta| meta.written_by).unwrap_or_default(),
+            supported: target,
+        });
+    }
+
+    fs::create_dir_all(dir)?;
+    let mut current = DatadirMeta {
+        version: from,
+        written_by: written_by.to_owned(),
+    };
+    for migration in migrations
+        .iter()
+        .filter(|migration| migration.version > from)
+    {
+        on_migration(migration);
+        (migration.run)(dir).map_err(|reason| MigrationError::Failed {
+            version: migration.version,
+            description: migration.description,
+            reason,
+        })?;
+        current.version = migration.version;
+        current.store(&meta_path)?;
+    }
+
+    current.version = target;
+    if meta.as_ref() != Some(&current) {
+        current.store(&meta_path)?;
+    }
+    Ok(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write};
+
+    use super::*;
+    use crate::fs::TestDir;
+
+    /// Appends the version of a migration to `migrations.log`, so tests see which ones ran.
+    fn log(dir: &Path, version: u32) -> Result<(), String> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join("migrations.log"))
+            .map_err(|e| e.to_string())?;
+        writeln!(file, "{version}").map_err(|e| e.to_string())
+    }
+
+    fn to_v2(dir: &Path) -> Result<(), String> {
+        log(dir, 2)
+    }
+
+    fn to_v3(dir: &Path) -> Result<(), String> {
+        log(dir, 3)
+    }
+
+    fn failing_v3(_: &Path) -> Result<(), String> {
+        Err("disk full".to_owned())
+    }
+
+    const MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 2,
+            description: "second layout",
+            run: to_v2,
+        },
+        Migration {
+            version: 3,
+            description: "third layout",
+            run: to_v3,
+        },
+    ];
+
+    fn run(datadir: &Datadir, migrations: &[Migration]) -> (Result<u32, MigrationError>, Vec<u32>) {
+        let mut started = Vec::new();
+        let result = migrate(datadir, migrations, 3, "mojave/test", |migration| {
+            started.push(migration.version)
+        });
+        (result, started)
+    }
+
+    fn ran(dir: &Path) -> String {
+        fs::read_to_string(dir.join("migrations.log")).unwrap_or_default()
+    }
+
+    fn version(datadir: &Datadir) -> Option<u32> {
+        DatadirMeta::load(&datadir.meta_file().unwrap())
+            .unwrap()
+            .map(|meta| meta.version)
+    }
+
+    #[test]
+    fn shipped_migrations_lead_to_the_current_version() {
+        let mut version = UNVERSIONED;
+        for migration in super::MIGRATIONS {
+            assert!(migration.version > version, "{migration:?} is out of order");
+            version = migration.version;
+        }
+        assert_eq!(version, DATADIR_VERSION);
+    }
+
+    #[test]
+    fn runs_missing_migrations_in_order() {
+        let dir = TestDir::new("migration-order");
+        let datadir = Datadir::Disk(dir.path().to_owned());
+
+        let (result, started) = run(&datadir, MIGRATIONS);
+        assert_eq!(result.unwrap(), UNVERSIONED);
+        assert_eq!(started, [2, 3]);
+        assert_eq!(ran(dir.path()), "2\n3\n");
+        let meta = DatadirMeta::load(&datadir.meta_file().unwrap()).unwrap();
+        assert_eq!(
+            meta,
+            Some(DatadirMeta {
+                version: 3,
+                written_by: "mojave/test".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn current_datadir_is_left_alone() {
+        let dir = TestDir::new("migration-idempotent");
+        let datadir = Datadir::Disk(dir.path().to_owned());
+        run(&datadir, MIGRATIONS).0.unwrap();
+
+        let (result, started) = run(&datadir, MIGRATIONS);
+        assert_eq!(result.unwrap(), 3);
+        assert!(started.is_empty());
+        assert_eq!(ran(dir.path()), "2\n3\n");
+    }
+
+    #[test]
+    fn only_newer_migrations_run() {
+        let dir = TestDir::new("migration-partial");
+        let datadir = Datadir::Disk(dir.path().to_owned());
+        DatadirMeta {
+            version: 2,
+            written_by: "mojave/old".to_owned(),
+        }
+        .store(&datadir.meta_file().unwrap())
+        .unwrap();
+
+        let (result, started) = run(&datadir, MIGRATIONS);
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(started, [3]);
+        assert_eq!(ran(dir.path()), "3\n");
+        assert_eq!(version(&datadir), Some(3));
+    }
+
+    #[test]
+    fn failed_migration_keeps_the_last_version_reached() {
+        let dir = TestDir::new("migration-failed");
+        let datadir = Datadir::Disk(dir.path().to_owned());
+        let failing = [
+            Migration { ..MIGRATIONS[0] },
+            Migration {
+                run: failing_v3,
+                ..MIGRATIONS[1]
+            },
+        ];
+
+        let (result, _) = run(&datadir, &failing);
+        assert!(matches!(
+            result,
+            Err(MigrationError::Failed { version: 3, reason, .. }) if reason == "disk full"
+        ));
+        assert_eq!(version(&datadir), Some(2));
+        assert!(!dir.path().join("mojave.meta.tmp").exists());
+
+        // Once fixed, only the failed migration runs again.
+        let (result, started) = run(&datadir, MIGRATIONS);
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(started, [3]);
+        assert_eq!(ran(dir.path()), "2\n3\n");
+        assert_eq!(version(&datadir), Some(3));
+    }
+
+    #[test]
+    fn newer_datadir_is_refused_untouched() {
+        let dir = TestDir::new("migration-too-new");
+        let datadir = Datadir::Disk(dir.path().to_owned());
+        let meta_path = datadir.meta_file().unwrap();
+        let newer = DatadirMeta {
+            version: 4,
+            written_by: "mojave/9.0.0".to_owned(),
+        };
+        newer.store(&meta_path).unwrap();
+
+        let (result, started) = run(&datadir, MIGRATIONS);
+        assert!(matches!(
+            result,
+            Err(MigrationError::TooNew { version: 4, ref written_by, supported: 3 })
+                if written_by == "mojave/9.0.0"
+        ));
+        assert!(started.is_empty());
+        assert_eq!(DatadirMeta::load(&meta_path).unwrap(), Some(newer));
+        assert_eq!(ran(dir.path()), "");
+    }
+
+    #[test]
+    fn memory_datadir_is_always_current() {
+        let (result, started) = run(&Datadir::Memory, MIGRATIONS);
+        assert_eq!(result.unwrap(), 3);
+        assert!(started.is_empty());
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::fs::write_atomic;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProposalRecordError {
+    #[error("Refusing to propose at height {attempted}, already proposed at height {last}")]
+    Regression { attempted: u64, last: u64 },
+    #[error("Refusing to propose a different block at height {0}, already proposed there")]
+    Conflict(u64),
+    #[error("Failed to access proposal record file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid proposal record file: {0}")]
+    Parse(String),
+}
+
+/// On-disk layout of the proposal record file.
+#[derive(Serialize, Deserialize)]
+struct ProposalRecordFile {
+    height: String,
+    block: String,
+}
+
+/// Last block a validator proposed, so a restarted validator proposes the same block again at
+/// that height instead of building a different one.
+///
+/// This protects no key. CometBFT signs proposals and votes with its own validator key and keeps
+/// its double-sign protection in its `priv_validator_state.json`, the app only builds the block.
+/// Sequencer blocks and L1 commitments are signed inside `ethrex_l2`, which has no hook before a
+/// signature is released, so only the datadir lock keeps a second sequencer off the same keys.
+#[derive(Debug)]
+pub struct ProposalRecord {
+    path: PathBuf,
+    height: u64,
+    block: Option<Vec<u8>>,
+}
+
+impl ProposalRecord {
+    /// Loads the record at `path`. A missing file means nothing was proposed yet.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, ProposalRecordError> {
+        let path = path.into();
+        let (height, block) = match fs::read_to_string(&path) {
+            Ok(contents) => {
+                let file: ProposalRecordFile = serde_json::from_str(&contents)
+                    .map_err(|e| ProposalRecordError::Parse(e.to_string()))?;
+                let height = file
+                    .height
+                    .parse()
+                    .map_err(|e| ProposalRecordError::Parse(format!("height: {e}")))?;
+                let block = hex::decode(file.block)
+                    .map_err(|e| ProposalRecordError::Parse(format!("block: {e}")))?;
+                (height, Some(block))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path,
+            height,
+            block,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Height of the last proposal and its block, `(0, None)` before the first one.
+    pub fn last_proposed(&self) -> (u64, Option<&[u8]>) {
+        (self.height, self.block.as_deref())
+    }
+
+    /// Checks that proposing `block` at `height` doesn't contradict an earlier proposal.
+    pub fn check(&self, height: u64, block: &[u8]) -> Result<(), ProposalRecordError> {
+        if self.block.is_none() {
+            return Ok(());
+        }
+        if height < self.height {
+            return Err(ProposalRecordError::Regression {
+                attempted: height,
+                last: self.height,
+            });
+        }
+        if height == self.height && self.block.as_deref() != Some(block) {
+            return Err(ProposalRecordError::Conflict(height));
+        }
+        Ok(())
+    }
+
+    /// Checks the proposal and persists it. The block may only be proposed once this returns.
+    pub fn record(&mut self, height: u64, block: &[u8]) -> Result<(), ProposalRecordError> {
+        self.check(height, block)?;
+        if self.block.is_some() && height == self.height {
+            return Ok(());
+        }
+
+        let file = ProposalRecordFile {
+            height: height.to_string(),
+            block: hex::encode(block),
+        };
+        let contents = serde_json::to_vec_pretty(&file)
+            .map_err(|e| ProposalRecordError::Parse(e.to_string()))?;
+        write_atomic(&self.path, &contents)?;
+
+        self.height = height;
+        self.block = Some(block.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::TestDir;
+
+    #[test]
+    fn missing_file_starts_from_scratch() {
+        let dir = TestDir::new("proposal-record-missing");
+        let record = ProposalRecord::load(dir.path().join("proposal.json")).unwrap();
+
+        assert_eq!(record.last_proposed(), (0, None));
+    }
+
+    #[test]
+    fn refuses_lower_heights() {
+        let dir = TestDir::new("proposal-record-regression");
+        let mut record = ProposalRecord::load(dir.path().join("proposal.json")).unwrap();
+        record.record(10, b"block").unwrap();
+
+        assert!(matches!(
+            record.record(9, b"block"),
+            Err(ProposalRecordError::Regression {
+                attempted: 9,
+                last: 10
+            })
+        ));
+        assert_eq!(record.last_proposed(), (10, Some(&b"block"[..])));
+    }
+
+    #[test]
+    fn refuses_a_different_block_at_the_same_height() {
+        let dir = TestDir::new("proposal-record-conflict");
+        let mut record = ProposalRecord::load(dir.path().join("proposal.json")).unwrap();
+        record.record(3, b"block a").unwrap();
+
+        assert!(matches!(
+            record.record(3, b"block b"),
+            Err(ProposalRecordError::Conflict(3))
+        ));
+        assert_eq!(record.last_proposed(), (3, Some(&b"block a"[..])));
+    }
+
+    #[test]
+    fn allows_proposing_the_same_block_again() {
+        let dir = TestDir::new("proposal-record-repropose");
+        let mut record = ProposalRecord::load(dir.path().join("proposal.json")).unwrap();
+        record.record(3, b"block").unwrap();
+
+        record.record(3, b"block").unwrap();
+        record.record(4, b"next").unwrap();
+        assert_eq!(record.last_proposed(), (4, Some(&b"next"[..])));
+    }
+
+    #[test]
+    fn first_proposal_can_be_at_any_height() {
+        let dir = TestDir::new("proposal-record-first");
+        let mut record = ProposalRecord::load(dir.path().join("proposal.json")).unwrap();
+
+        record.record(0, b"block").unwrap();
+        assert_eq!(record.last_proposed(), (0, Some(&b"block"[..])));
+    }
+
+    #[test]
+    fn persists_atomically_across_reloads() {
+        let dir = TestDir::new("proposal-record-reload");
+        let path = dir.path().join("proposal.json");
+        ProposalRecord::load(&path)
+            .unwrap()
+            .record(7, b"block")
+            .unwrap();
+
+        let mut reloaded = ProposalRecord::load(&path).unwrap();
+        assert_eq!(reloaded.last_proposed(), (7, Some(&b"block"[..])));
+        assert!(matches!(
+            reloaded.record(6, b"block"),
+            Err(ProposalRecordError::Regression { .. })
+        ));
+        assert!(matches!(
+            reloaded.record(7, b"other"),
+            Err(ProposalRecordError::Conflict(7))
+        ));
+        // The record is written to a temporary file and renamed over the old one.
+        let files: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["proposal.json"]);
+    }
+
+    #[test]
+    fn rejects_corrupt_files() {
+        let dir = TestDir::new("proposal-record-corrupt");
+        let path = dir.path().join("proposal.json");
+        fs::write(&path, r#"{"height":"1","block":"not hex"}"#).unwrap();
+
+        assert!(matches!(
+            ProposalRecord::load(&path),
+            Err(ProposalRecordError::Parse(_))
+        ));
+    }
+}